@@ -0,0 +1,162 @@
+//! Measures how long the worker waits for the vehicle state lock while telemetry
+//! is being forwarded, comparing the previous forwarder, which serialized and sent
+//! while holding the lock, with the current one, which only copies a snapshot.
+//!
+//! Run with `cargo bench --bench forwarding`.
+
+#[allow(dead_code)]
+#[path = "../src/snapshot.rs"]
+mod snapshot;
+
+use common::comm::{CompositeValveState, Measurement, Unit, ValveState, VehicleState};
+use std::{hint::black_box, net::UdpSocket, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread, time::{Duration, Instant}};
+
+/// Roughly the size of a full vehicle mapping.
+const VALVES: usize = 64;
+const SENSORS: usize = 512;
+
+/// How many state updates the simulated worker makes in each run.
+const UPDATES: usize = 20_000;
+
+/// How often the simulated worker updates the state, about the rate at which boards send data.
+const UPDATE_PERIOD: Duration = Duration::from_micros(50);
+
+/// How often the simulated forwarder sends telemetry. Faster than the real
+/// forwarder, to make contention easier to measure.
+const FORWARD_PERIOD: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy)]
+enum Forwarder {
+	/// Serializes with `to_allocvec` and sends while holding the lock.
+	Locked,
+
+	/// Copies into a snapshot under the lock, then serializes into a reused buffer and sends.
+	Snapshot,
+}
+
+fn main() {
+	for forwarder in [None, Some(Forwarder::Locked), Some(Forwarder::Snapshot)] {
+		let name = match forwarder {
+			None => "no forwarding",
+			Some(Forwarder::Locked) => "serialize and send under lock",
+			Some(Forwarder::Snapshot) => "snapshot, then serialize and send",
+		};
+
+		let (mut waits, forwards) = run(forwarder);
+		waits.sort_unstable();
+
+		println!("{name}:");
+		println!("  worker lock wait: p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}", percentile(&waits, 0.5), percentile(&waits, 0.99), percentile(&waits, 0.999), waits[waits.len() - 1]);
+
+		if let Some((count, held, busy)) = forwards {
+			println!("  forwarder: {count} sends, lock held {:?} and {:?} total per send", held / count.max(1) as u32, busy / count.max(1) as u32);
+		}
+	}
+}
+
+/// Runs the simulated worker against the given forwarder, returning how long
+/// each update waited for the lock, along with the forwarder's totals.
+fn run(forwarder: Option<Forwarder>) -> (Vec<Duration>, Option<(usize, Duration, Duration)>) {
+	let vehicle_state = Arc::new(Mutex::new(vehicle_state()));
+	let running = Arc::new(AtomicBool::new(true));
+
+	let forwarding = forwarder.map(|forwarder| {
+		let vehicle_state = vehicle_state.clone();
+		let running = running.clone();
+
+		thread::spawn(move || forward(forwarder, &vehicle_state, &running))
+	});
+
+	let sensors: Vec<String> = (0..SENSORS).map(|sensor| format!("sensor_{sensor}")).collect();
+	let mut waits = Vec::with_capacity(UPDATES);
+
+	for update in 0..UPDATES {
+		let started = Instant::now();
+		let mut state = vehicle_state.lock().unwrap();
+		waits.push(started.elapsed());
+
+		if let Some(reading) = state.sensor_readings.get_mut(&sensors[update % SENSORS]) {
+			reading.value = update as f64;
+		}
+
+		drop(state);
+		thread::sleep(UPDATE_PERIOD);
+	}
+
+	running.store(false, Ordering::Relaxed);
+	let forwards = forwarding.map(|handle| handle.join().unwrap());
+
+	(waits, forwards)
+}
+
+/// Forwards telemetry to a local socket until stopped, returning how many sends
+/// were made, how long the lock was held in total, and how long they took in total.
+fn forward(forwarder: Forwarder, vehicle_state: &Mutex<VehicleState>, running: &AtomicBool) -> (usize, Duration, Duration) {
+	let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let destination = receiver.local_addr().unwrap();
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+	let mut snapshot = VehicleState::new();
+	let mut serialized = Vec::new();
+	let mut count = 0;
+	let mut held = Duration::ZERO;
+	let mut busy = Duration::ZERO;
+
+	while running.load(Ordering::Relaxed) {
+		let started = Instant::now();
+
+		match forwarder {
+			Forwarder::Locked => {
+				let state = vehicle_state.lock().unwrap();
+				let locked = Instant::now();
+				let serialized = postcard::to_allocvec(&*state).unwrap();
+				let _ = socket.send_to(&serialized, destination);
+				drop(state);
+				held += locked.elapsed();
+			},
+			Forwarder::Snapshot => {
+				let state = vehicle_state.lock().unwrap();
+				let locked = Instant::now();
+				snapshot::copy_state(&mut snapshot, &state);
+				drop(state);
+				held += locked.elapsed();
+
+				snapshot::serialize_into(&snapshot, &mut serialized).unwrap();
+				let _ = socket.send_to(&serialized, destination);
+			},
+		}
+
+		busy += started.elapsed();
+		count += 1;
+
+		black_box(&serialized);
+		thread::sleep(FORWARD_PERIOD);
+	}
+
+	(count, held, busy)
+}
+
+/// A vehicle state with every valve and sensor populated.
+fn vehicle_state() -> VehicleState {
+	let mut state = VehicleState::new();
+
+	for valve in 0..VALVES {
+		state.valve_states.insert(format!("valve_{valve}"), CompositeValveState {
+			commanded: ValveState::Closed,
+			actual: ValveState::Closed,
+		});
+	}
+
+	for sensor in 0..SENSORS {
+		state.sensor_readings.insert(format!("sensor_{sensor}"), Measurement {
+			value: 0.0,
+			unit: Unit::Psi,
+		});
+	}
+
+	state
+}
+
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+	sorted[((sorted.len() - 1) as f64 * fraction) as usize]
+}
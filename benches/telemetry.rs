@@ -0,0 +1,136 @@
+//! Compares the bytes sent and time taken per frame by each telemetry encoding.
+//! That delta-encoded frames decode back to what they were encoded from is
+//! checked by the tests in `encoding.rs`.
+//!
+//! Run with `cargo bench --bench telemetry`.
+
+#[allow(dead_code)]
+#[path = "../src/encoding.rs"]
+mod encoding;
+
+use common::comm::{CompositeValveState, Measurement, Unit, ValveState, VehicleState};
+use encoding::{Encoder, TelemetryEnvelope, TelemetryFrame};
+use std::{borrow::Cow, collections::HashMap, time::{Duration, Instant}};
+
+/// A large vehicle mapping, whose full frames do not fit in a single datagram.
+const VALVES: usize = 64;
+const SENSORS: usize = 1_024;
+
+/// How many sensors change between consecutive frames, about what a 10 ms
+/// period sees when most readings are slow-moving.
+const CHANGED_PER_FRAME: usize = 64;
+
+const FRAMES: usize = 10_000;
+const KEYFRAME_INTERVAL: u32 = 100;
+const FRAGMENT_SIZE: usize = 1_200;
+
+fn main() {
+	let mut state = vehicle_state();
+	let mut acquired = acquisition_times();
+	let mut frame_bytes = 0;
+	let mut elapsed = Duration::ZERO;
+
+	for frame in 0..FRAMES {
+		change(&mut state, &mut acquired, frame);
+
+		let started = Instant::now();
+
+		let envelope = TelemetryEnvelope {
+			counter: frame as u64,
+			timestamp: frame as f64,
+			frame: TelemetryFrame::Full {
+				state: Cow::Borrowed(&state),
+				acquired: Cow::Borrowed(&acquired),
+			},
+		};
+
+		let serialized = postcard::to_allocvec(&envelope).unwrap();
+		elapsed += started.elapsed();
+		frame_bytes += serialized.len();
+	}
+
+	report("full", frame_bytes, None, elapsed);
+
+	for compress in [false, true] {
+		let mut state = vehicle_state();
+		let mut acquired = acquisition_times();
+		let mut encoder = Encoder::new(KEYFRAME_INTERVAL, compress, FRAGMENT_SIZE);
+		let mut datagrams = Vec::new();
+		let mut frame_bytes = 0;
+		let mut largest = 0;
+		let mut elapsed = Duration::ZERO;
+
+		for frame in 0..FRAMES {
+			change(&mut state, &mut acquired, frame);
+			datagrams.clear();
+
+			let started = Instant::now();
+			encoder.encode(frame as u64, frame as f64, &state, &acquired, |datagram| datagrams.push(datagram.to_vec())).unwrap();
+			elapsed += started.elapsed();
+
+			for datagram in &datagrams {
+				frame_bytes += datagram.len();
+				largest = largest.max(datagram.len());
+			}
+		}
+
+		report(if compress { "delta, compressed" } else { "delta" }, frame_bytes, Some(largest), elapsed);
+	}
+}
+
+fn report(name: &str, bytes: usize, largest_datagram: Option<usize>, elapsed: Duration) {
+	print!("{name}: {} bytes and {:?} per frame", bytes / FRAMES, elapsed / FRAMES as u32);
+
+	match largest_datagram {
+		Some(largest) => println!(", largest datagram {largest} bytes"),
+		None => println!(", sent as a single datagram"),
+	}
+}
+
+/// Changes a rotating subset of the sensors, along with when they were
+/// acquired, and toggles a single valve.
+fn change(state: &mut VehicleState, acquired: &mut HashMap<String, f64>, frame: usize) {
+	for offset in 0..CHANGED_PER_FRAME {
+		let sensor = format!("sensor_{}", (frame * CHANGED_PER_FRAME + offset) % SENSORS);
+
+		if let Some(reading) = state.sensor_readings.get_mut(&sensor) {
+			reading.value = frame as f64 + offset as f64 / 10.0;
+		}
+
+		if let Some(time) = acquired.get_mut(&sensor) {
+			*time = frame as f64 / 100.0;
+		}
+	}
+
+	if let Some(valve) = state.valve_states.get_mut(&format!("valve_{}", frame % VALVES)) {
+		valve.actual = if valve.actual == ValveState::Open { ValveState::Closed } else { ValveState::Open };
+	}
+}
+
+/// A vehicle state with every valve and sensor populated.
+fn vehicle_state() -> VehicleState {
+	let mut state = VehicleState::new();
+
+	for valve in 0..VALVES {
+		state.valve_states.insert(format!("valve_{valve}"), CompositeValveState {
+			commanded: ValveState::Closed,
+			actual: ValveState::Closed,
+		});
+	}
+
+	for sensor in 0..SENSORS {
+		state.sensor_readings.insert(format!("sensor_{sensor}"), Measurement {
+			value: 0.0,
+			unit: Unit::Psi,
+		});
+	}
+
+	state
+}
+
+/// An acquisition time for every sensor.
+fn acquisition_times() -> HashMap<String, f64> {
+	(0..SENSORS)
+		.map(|sensor| (format!("sensor_{sensor}"), 0.0))
+		.collect()
+}
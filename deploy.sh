@@ -1,3 +1,25 @@
 #!/bin/bash
+#
+# Usage: ./deploy.sh <hostname> [directory]
+#
+# Copies the flight computer to debian@<hostname>.local. If a directory is given,
+# it is installed as /etc/flight, holding flight.toml, control.key, peer.key, and
+# boards/<board id>.key, as described in README.md.
 
-scp target/armv7-unknown-linux-gnueabihf/debug/fc debian@$1.local:~/
\ No newline at end of file
+set -e
+
+host=debian@$1.local
+
+scp target/armv7-unknown-linux-gnueabihf/debug/flight $host:~/
+
+if [ -n "$2" ]; then
+	scp -r "$2" $host:~/flight-settings
+
+	# the keys must only be readable by the user running the flight computer, which
+	# must also be able to write the configuration it persists across restarts
+	ssh -t $host "sudo mkdir -p /etc/flight /var/lib/flight \
+		&& sudo cp -r ~/flight-settings/. /etc/flight/ \
+		&& rm -rf ~/flight-settings \
+		&& sudo chown -R debian:debian /etc/flight /var/lib/flight \
+		&& sudo chmod -R go-rwx /etc/flight"
+fi
@@ -0,0 +1,116 @@
+use common::comm::{BoardId, VehicleState};
+use serde::{Deserialize, Serialize};
+use crate::ABORT_HISTORY_LENGTH;
+use std::{collections::VecDeque, fmt, thread, time::{SystemTime, UNIX_EPOCH}};
+
+/// The reason that an abort was requested.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AbortCause {
+	/// A board has not been heard from for longer than `TIME_TIL_DEATH`.
+	LossOfComms(BoardId),
+
+	/// A heartbeat could not be sent to a board.
+	HeartbeatFailure(BoardId),
+
+	/// A message bound for a board could not be serialized.
+	SerializationFailure(Option<BoardId>),
+
+	/// A switchboard thread lost its socket or one of its channels.
+	SwitchboardFailure(String),
+
+	/// The operator sent an abort instruction from the control server.
+	Operator,
+
+	/// A running sequence called `abort()`.
+	Sequence(String),
+
+	/// The script of the named trigger called `abort()`.
+	Trigger(String),
+
+	/// The named valve did not reach its commanded state within its response time.
+	ValveMismatch(String),
+
+	/// The connection to every control server was lost and the loss-of-ground policy is to abort.
+	LossOfGround,
+
+	/// The other computer, flight or ground, aborted for the given reason.
+	Peer(Box<AbortCause>),
+}
+
+impl fmt::Display for AbortCause {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::LossOfComms(board_id) => write!(f, "loss of comms with {board_id}"),
+			Self::HeartbeatFailure(board_id) => write!(f, "failed to send heartbeat to {board_id}"),
+			Self::SerializationFailure(Some(board_id)) => write!(f, "failed to serialize message for {board_id}"),
+			Self::SerializationFailure(None) => write!(f, "failed to serialize message"),
+			Self::SwitchboardFailure(reason) => write!(f, "switchboard failure: {reason}"),
+			Self::Operator => write!(f, "operator command"),
+			Self::Sequence(name) => write!(f, "requested by sequence '{name}'"),
+			Self::Trigger(name) => write!(f, "requested by trigger '{name}'"),
+			Self::ValveMismatch(name) => write!(f, "valve '{name}' did not reach its commanded state"),
+			Self::LossOfGround => write!(f, "loss of connection to every control server"),
+			Self::Peer(cause) => write!(f, "other computer aborted due to {cause}"),
+		}
+	}
+}
+
+/// A single request to abort, recorded along with the context it was made in.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AbortEvent {
+	/// Seconds since the UNIX epoch at which the abort was requested.
+	pub timestamp: f64,
+
+	/// Name of the requesting thread, or its ID if the thread is unnamed.
+	pub thread: String,
+
+	/// Why the abort was requested.
+	pub cause: AbortCause,
+
+	/// The vehicle state at the moment the abort was requested.
+	pub vehicle_state: VehicleState,
+}
+
+impl AbortEvent {
+	/// Creates an event attributed to the calling thread.
+	pub fn new(cause: AbortCause, vehicle_state: VehicleState) -> Self {
+		let current = thread::current();
+
+		let thread = current
+			.name()
+			.map(str::to_owned)
+			.unwrap_or_else(|| format!("{:?}", current.id()));
+
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|time| time.as_secs_f64())
+			.unwrap_or(0.0);
+
+		AbortEvent { timestamp, thread, cause, vehicle_state }
+	}
+}
+
+/// Latch which guarantees that the abort sequence is only run once, no matter
+/// how many threads request an abort concurrently.
+///
+/// Once set, the latch stays set until the operator explicitly resets it.
+#[derive(Debug, Default)]
+pub struct AbortLatch {
+	/// Whether an abort has been triggered since the last reset.
+	pub aborted: bool,
+
+	/// The most recent abort events, oldest first, kept across resets so they
+	/// can be queried later. Holds at most `ABORT_HISTORY_LENGTH` events.
+	pub history: VecDeque<AbortEvent>,
+}
+
+impl AbortLatch {
+	/// Records an event, discarding the oldest one if the history is full.
+	pub fn record(&mut self, event: AbortEvent) {
+		if self.history.len() == ABORT_HISTORY_LENGTH {
+			self.history.pop_front();
+		}
+
+		self.history.push_back(event);
+	}
+}
@@ -0,0 +1,316 @@
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Instant};
+use crate::{auth, event::{self, Event}, protocol::{self, FlightReport}, state::SharedState, ABORT_SEQUENCE_NAME, ARM_TOKEN_LIFETIME, TRIGGER_SEQUENCE_PREFIX};
+
+/// Whether the vehicle may be commanded to do anything hazardous.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum ArmState {
+	/// Sequences and triggers may run, but only the safing sequence may actuate valves.
+	#[default]
+	Safe,
+
+	/// Sequences and triggers may actuate valves.
+	Armed,
+
+	/// A sequence started by the operator while armed is running. The vehicle
+	/// returns to `Armed` once it finishes and cannot be disarmed until then.
+	Firing,
+
+	/// The flight computer has aborted. Only the abort and safing sequences may
+	/// actuate valves, and the vehicle returns to `Safe` once the abort is reset.
+	Aborted,
+}
+
+impl ArmState {
+	/// Whether a sequence may actuate valves in this state, given whether it is
+	/// the abort sequence or the safing sequence run upon losing the ground.
+	pub fn permits_actuation(self, abort_sequence: bool, safing_sequence: bool) -> bool {
+		match self {
+			Self::Safe => safing_sequence,
+			Self::Armed | Self::Firing => true,
+			Self::Aborted => abort_sequence || safing_sequence,
+		}
+	}
+}
+
+impl fmt::Display for ArmState {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Safe => write!(f, "safe"),
+			Self::Armed => write!(f, "armed"),
+			Self::Firing => write!(f, "firing"),
+			Self::Aborted => write!(f, "aborted"),
+		}
+	}
+}
+
+/// The arm state along with the outstanding confirmation token, if any.
+#[derive(Debug, Default)]
+pub struct Arming {
+	pub state: ArmState,
+
+	/// The token which must be echoed back by the operator to arm, and when it was issued.
+	token: Option<(u64, Instant)>,
+}
+
+/// Issues a confirmation token which the operator must send back to arm the
+/// vehicle, returning when it was issued.
+pub fn request_arm(shared: &SharedState) -> Option<Instant> {
+	let mut arming = shared.arming.lock().unwrap();
+
+	if arming.state != ArmState::Safe {
+		fail!("Refusing to issue arm token because the vehicle is {}.", arming.state);
+		return None;
+	}
+
+	let token = match auth::random_u64() {
+		Ok(token) => token,
+		Err(error) => {
+			fail!("Failed to generate arm token: {error}");
+			return None;
+		},
+	};
+
+	let issued = Instant::now();
+	arming.token = Some((token, issued));
+	drop(arming);
+
+	pass!("Issued arm token. The operator must confirm within {ARM_TOKEN_LIFETIME:?}.");
+	protocol::send_report(&shared.control_sockets, &FlightReport::ArmToken(token));
+	Some(issued)
+}
+
+/// Discards the token issued at the given time if it is still outstanding,
+/// letting the operator know that it can no longer be used.
+pub fn expire_token(shared: &SharedState, issued: Instant) {
+	let mut arming = shared.arming.lock().unwrap();
+
+	// the token has since been used or replaced
+	if !matches!(arming.token, Some((_, at)) if at == issued) {
+		return;
+	}
+
+	arming.token = None;
+	drop(arming);
+
+	warn!("Arm token expired before the operator confirmed it.");
+	protocol::send_report(&shared.control_sockets, &FlightReport::ArmTokenExpired);
+}
+
+/// Arms the vehicle if the token matches the one most recently issued and has
+/// not expired. Either way, the token cannot be used again.
+pub fn arm(shared: &SharedState, token: u64) {
+	let mut arming = shared.arming.lock().unwrap();
+
+	if arming.state != ArmState::Safe {
+		fail!("Refusing to arm because the vehicle is {}.", arming.state);
+		return;
+	}
+
+	match arming.token.take() {
+		Some((issued, at)) if issued == token && at.elapsed() <= ARM_TOKEN_LIFETIME => {},
+		Some((issued, _)) if issued == token => {
+			fail!("Refusing to arm because the arm token has expired.");
+			return;
+		},
+		_ => {
+			fail!("Refusing to arm because the arm token does not match the one issued.");
+			return;
+		},
+	}
+
+	drop(arming);
+	transition_from(shared, &[ArmState::Safe], ArmState::Armed);
+}
+
+/// Returns the vehicle to `Safe`, unless a sequence is firing.
+pub fn disarm(shared: &SharedState) {
+	if !transition_from(shared, &[ArmState::Armed], ArmState::Safe) {
+		let state = shared.arming.lock().unwrap().state;
+		fail!("Refusing to disarm because the vehicle is {state}.");
+	}
+}
+
+/// Whether a running sequence keeps the vehicle `Firing`, given the name of the
+/// safing sequence, if any. Only sequences started by the operator do, rather
+/// than those run by triggers, the abort, or the loss of the ground.
+pub fn keeps_firing(sequence: &str, safing_sequence: Option<&str>) -> bool {
+	!sequence.starts_with(TRIGGER_SEQUENCE_PREFIX)
+		&& sequence != ABORT_SEQUENCE_NAME
+		&& Some(sequence) != safing_sequence
+}
+
+/// Moves from `Armed` to `Firing` when the operator starts a sequence.
+pub fn start_firing(shared: &SharedState) {
+	transition_from(shared, &[ArmState::Armed], ArmState::Firing);
+}
+
+/// Returns from `Firing` to `Armed` once a sequence started by the operator finishes.
+pub fn finish_firing(shared: &SharedState) {
+	transition_from(shared, &[ArmState::Firing], ArmState::Armed);
+}
+
+/// Sets the arm state unconditionally, queueing a report of the change to the operator.
+pub fn transition(shared: &SharedState, state: ArmState) {
+	transition_from(shared, &[ArmState::Safe, ArmState::Armed, ArmState::Firing, ArmState::Aborted], state);
+}
+
+/// Sets the arm state if it is currently one of `from`, queueing a report of the
+/// change to the operator. Returns whether the vehicle is now in the new state.
+///
+/// The check and the change happen under one lock so that, for example, a
+/// sequence finishing cannot undo an abort which happened in the meantime. The
+/// report is sent by the main loop, so that a slow control connection cannot
+/// hold up whatever changed the state, such as an abort.
+fn transition_from(shared: &SharedState, from: &[ArmState], state: ArmState) -> bool {
+	let mut arming = shared.arming.lock().unwrap();
+
+	if arming.state == state {
+		return true;
+	}
+
+	if !from.contains(&arming.state) {
+		return false;
+	}
+
+	pass!("Vehicle is now {state} (was {}).", arming.state);
+	arming.state = state;
+	arming.token = None;
+	drop(arming);
+
+	event::notify(shared, Event::ArmState(state));
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{sync::mpsc, time::Duration};
+	use crate::{persistence::PersistedConfiguration, settings::Settings};
+
+	fn shared_state() -> SharedState {
+		SharedState::new(PersistedConfiguration::default(), Settings::flight(), mpsc::channel().0)
+	}
+
+	fn state(shared: &SharedState) -> ArmState {
+		shared.arming.lock().unwrap().state
+	}
+
+	fn issued_token(shared: &SharedState) -> u64 {
+		request_arm(shared).unwrap();
+		shared.arming.lock().unwrap().token.unwrap().0
+	}
+
+	#[test]
+	fn arming_requires_the_issued_token_once() {
+		let shared = shared_state();
+
+		arm(&shared, 0);
+		assert_eq!(state(&shared), ArmState::Safe);
+
+		let token = issued_token(&shared);
+		arm(&shared, token.wrapping_add(1));
+		assert_eq!(state(&shared), ArmState::Safe);
+
+		// a mismatched token is consumed too
+		arm(&shared, token);
+		assert_eq!(state(&shared), ArmState::Safe);
+
+		let token = issued_token(&shared);
+		arm(&shared, token);
+		assert_eq!(state(&shared), ArmState::Armed);
+		assert!(request_arm(&shared).is_none());
+	}
+
+	#[test]
+	fn expired_token_does_not_arm() {
+		let shared = shared_state();
+		let token = issued_token(&shared);
+
+		let Some(issued) = Instant::now().checked_sub(ARM_TOKEN_LIFETIME + Duration::from_secs(1)) else {
+			return;
+		};
+
+		shared.arming.lock().unwrap().token = Some((token, issued));
+		arm(&shared, token);
+		assert_eq!(state(&shared), ArmState::Safe);
+
+		let token = issued_token(&shared);
+		let issued = shared.arming.lock().unwrap().token.unwrap().1;
+		expire_token(&shared, issued);
+		arm(&shared, token);
+		assert_eq!(state(&shared), ArmState::Safe);
+	}
+
+	#[test]
+	fn firing_cannot_be_disarmed_until_finished() {
+		let shared = shared_state();
+		transition(&shared, ArmState::Armed);
+
+		start_firing(&shared);
+		assert_eq!(state(&shared), ArmState::Firing);
+
+		disarm(&shared);
+		assert_eq!(state(&shared), ArmState::Firing);
+
+		finish_firing(&shared);
+		assert_eq!(state(&shared), ArmState::Armed);
+
+		disarm(&shared);
+		assert_eq!(state(&shared), ArmState::Safe);
+
+		// firing only starts from armed
+		start_firing(&shared);
+		assert_eq!(state(&shared), ArmState::Safe);
+	}
+
+	#[test]
+	fn abort_is_not_undone_by_a_sequence_finishing() {
+		let shared = shared_state();
+		transition(&shared, ArmState::Armed);
+		start_firing(&shared);
+
+		transition(&shared, ArmState::Aborted);
+		finish_firing(&shared);
+		assert_eq!(state(&shared), ArmState::Aborted);
+
+		disarm(&shared);
+		assert!(request_arm(&shared).is_none());
+		assert_eq!(state(&shared), ArmState::Aborted);
+	}
+
+	#[test]
+	fn changes_are_reported_through_the_main_loop() {
+		let (events_tx, events) = mpsc::channel();
+		let shared = SharedState::new(PersistedConfiguration::default(), Settings::flight(), events_tx);
+
+		transition(&shared, ArmState::Aborted);
+		assert!(matches!(events.try_recv(), Ok(Event::ArmState(ArmState::Aborted))));
+
+		// nothing changed, so there is nothing to report
+		transition(&shared, ArmState::Aborted);
+		finish_firing(&shared);
+		assert!(events.try_recv().is_err());
+	}
+
+	#[test]
+	fn only_operator_sequences_keep_firing() {
+		assert!(keeps_firing("burn", Some("safe")));
+		assert!(keeps_firing("burn", None));
+		assert!(!keeps_firing(&format!("{TRIGGER_SEQUENCE_PREFIX}overpressure"), None));
+		assert!(!keeps_firing(ABORT_SEQUENCE_NAME, None));
+		assert!(!keeps_firing("safe", Some("safe")));
+	}
+
+	#[test]
+	fn actuation_permitted_by_state() {
+		assert!(!ArmState::Safe.permits_actuation(false, false));
+		assert!(ArmState::Safe.permits_actuation(false, true));
+		assert!(ArmState::Armed.permits_actuation(false, false));
+		assert!(ArmState::Firing.permits_actuation(false, false));
+		assert!(!ArmState::Aborted.permits_actuation(false, false));
+		assert!(ArmState::Aborted.permits_actuation(true, false));
+		assert!(ArmState::Aborted.permits_actuation(false, true));
+	}
+}
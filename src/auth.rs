@@ -0,0 +1,402 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{fmt, fs::{self, File}, io::{self, Read, Write}, net::{Shutdown, TcpStream}, time::Duration};
+use crate::{CONTROL_HANDSHAKE_TIMEOUT, CONTROL_KEY_PATH, CONTROL_MAX_FRAME_SIZE, CONTROL_WRITE_TIMEOUT};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of the nonces exchanged during the handshake and of every MAC.
+const NONCE_SIZE: usize = 32;
+const TAG_SIZE: usize = 32;
+
+/// Length of the counter prefixed to every authenticated datagram.
+const COUNTER_SIZE: usize = 8;
+
+/// Mixed into every frame's MAC so that a frame cannot be reflected back to its sender.
+const FLIGHT_TO_SERVER: u8 = b'F';
+const SERVER_TO_FLIGHT: u8 = b'S';
+
+/// Why a frame could not be received.
+#[derive(Debug)]
+pub enum FrameError {
+	/// The connection failed or sent something which cannot be a frame. The
+	/// connection cannot be trusted to stay in sync and should be dropped.
+	Io(io::Error),
+
+	/// A whole frame was received, but its MAC or counter was invalid. It has
+	/// been discarded and the connection may continue to be used.
+	Unauthenticated,
+}
+
+impl fmt::Display for FrameError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(error) => write!(f, "{error}"),
+			Self::Unauthenticated => write!(f, "frame failed authentication"),
+		}
+	}
+}
+
+impl From<io::Error> for FrameError {
+	fn from(error: io::Error) -> Self {
+		Self::Io(error)
+	}
+}
+
+/// Receiving half of an authenticated control connection.
+#[derive(Debug)]
+pub struct FrameReader {
+	stream: TcpStream,
+	key: [u8; 32],
+
+	/// The lowest counter the next frame may have, so that frames cannot be replayed.
+	next_counter: u64,
+}
+
+/// Sending half of an authenticated control connection.
+#[derive(Debug)]
+pub struct FrameWriter {
+	stream: TcpStream,
+	key: [u8; 32],
+	counter: u64,
+}
+
+impl FrameReader {
+	/// Blocks until a frame is received, returning its payload only if it was
+	/// sent by the authenticated server and has not been received before.
+	///
+	/// A frame is a big-endian `u32` payload length, a big-endian `u64` counter,
+	/// the payload, and an HMAC-SHA256 of the direction, counter, and payload.
+	pub fn receive(&mut self) -> Result<Vec<u8>, FrameError> {
+		let mut header = [0; 12];
+		self.stream.read_exact(&mut header)?;
+
+		let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+		let counter = u64::from_be_bytes(header[4..].try_into().unwrap());
+
+		if length > CONTROL_MAX_FRAME_SIZE {
+			let error = io::Error::new(io::ErrorKind::InvalidData, format!("frame of {length} bytes exceeds the maximum size"));
+			return Err(FrameError::Io(error));
+		}
+
+		let mut payload = vec![0; length];
+		let mut tag = [0; TAG_SIZE];
+		self.stream.read_exact(&mut payload)?;
+		self.stream.read_exact(&mut tag)?;
+
+		let authentic = mac(&self.key, SERVER_TO_FLIGHT, counter, &payload)
+			.verify_slice(&tag)
+			.is_ok();
+
+		if !authentic || counter < self.next_counter {
+			return Err(FrameError::Unauthenticated);
+		}
+
+		self.next_counter = counter + 1;
+		Ok(payload)
+	}
+
+	/// Sets how long `receive` may block before failing with a timeout.
+	pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		self.stream.set_read_timeout(timeout)
+	}
+}
+
+impl FrameWriter {
+	/// Sends the payload as a single authenticated frame.
+	///
+	/// If the frame cannot be sent within `CONTROL_WRITE_TIMEOUT`, the connection
+	/// is shut down, since part of the frame may have been sent. The receiving
+	/// half then fails too, so the connection is dropped as if the server had left.
+	pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+		let length = u32::try_from(payload.len())
+			.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload is too large to frame"))?;
+
+		let tag = mac(&self.key, FLIGHT_TO_SERVER, self.counter, payload)
+			.finalize()
+			.into_bytes();
+
+		let mut frame = Vec::with_capacity(12 + payload.len() + TAG_SIZE);
+		frame.extend_from_slice(&length.to_be_bytes());
+		frame.extend_from_slice(&self.counter.to_be_bytes());
+		frame.extend_from_slice(payload);
+		frame.extend_from_slice(&tag);
+
+		self.counter += 1;
+
+		let result = self.stream.write_all(&frame);
+
+		if result.is_err() {
+			let _ = self.stream.shutdown(Shutdown::Both);
+		}
+
+		result
+	}
+}
+
+/// Reads the key shared with the control server from `CONTROL_KEY_PATH`.
+pub fn load_key() -> io::Result<Vec<u8>> {
+	let key = fs::read(CONTROL_KEY_PATH)?;
+
+	if key.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{CONTROL_KEY_PATH} is empty")));
+	}
+
+	Ok(key)
+}
+
+/// Performs mutual challenge-response authentication with the server using the
+/// pre-shared key, splitting the connection into halves which authenticate
+/// every frame with a key unique to this session.
+///
+/// 1. The flight computer sends a random nonce.
+/// 2. The server replies with its own nonce and a MAC over both, proving it knows the key.
+/// 3. The flight computer replies with a MAC over both, proving the same.
+pub fn authenticate(mut stream: TcpStream, psk: &[u8]) -> io::Result<(FrameReader, FrameWriter)> {
+	stream.set_read_timeout(Some(CONTROL_HANDSHAKE_TIMEOUT))?;
+
+	let mut flight_nonce = [0; NONCE_SIZE];
+	fill_random(&mut flight_nonce)?;
+	stream.write_all(&flight_nonce)?;
+
+	let mut server_nonce = [0; NONCE_SIZE];
+	let mut server_proof = [0; TAG_SIZE];
+	stream.read_exact(&mut server_nonce)?;
+	stream.read_exact(&mut server_proof)?;
+
+	handshake_mac(psk, b"server", &flight_nonce, &server_nonce)
+		.verify_slice(&server_proof)
+		.map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "server does not know the pre-shared key"))?;
+
+	let flight_proof = handshake_mac(psk, b"flight", &flight_nonce, &server_nonce)
+		.finalize()
+		.into_bytes();
+
+	stream.write_all(&flight_proof)?;
+	stream.set_read_timeout(None)?;
+	stream.set_write_timeout(Some(CONTROL_WRITE_TIMEOUT))?;
+
+	let key = handshake_mac(psk, b"session", &flight_nonce, &server_nonce)
+		.finalize()
+		.into_bytes()
+		.into();
+
+	let writer = FrameWriter { stream: stream.try_clone()?, key, counter: 0 };
+	let reader = FrameReader { stream, key, next_counter: 0 };
+
+	Ok((reader, writer))
+}
+
+/// Fills the buffer with bytes from the kernel's random number generator.
+pub fn fill_random(buffer: &mut [u8]) -> io::Result<()> {
+	File::open("/dev/urandom")?.read_exact(buffer)
+}
+
+/// A random `u64` from the kernel's random number generator, for tokens and IDs
+/// which must be neither guessed nor repeated.
+pub fn random_u64() -> io::Result<u64> {
+	let mut bytes = [0; 8];
+	fill_random(&mut bytes)?;
+	Ok(u64::from_ne_bytes(bytes))
+}
+
+/// Wraps a message in an authenticated datagram: a big-endian `u64` counter,
+/// the message, and an HMAC-SHA256 of the direction, counter, and message.
+///
+/// The direction identifies the sender, so that a datagram cannot be reflected
+/// back to it. Counters must never be reused under the same key.
+pub fn seal_datagram(key: &[u8], direction: u8, counter: u64, message: &[u8]) -> Vec<u8> {
+	let tag = mac(key, direction, counter, message)
+		.finalize()
+		.into_bytes();
+
+	let mut datagram = Vec::with_capacity(COUNTER_SIZE + message.len() + TAG_SIZE);
+	datagram.extend_from_slice(&counter.to_be_bytes());
+	datagram.extend_from_slice(message);
+	datagram.extend_from_slice(&tag);
+	datagram
+}
+
+/// Splits a datagram sealed by `seal_datagram` into its counter, message, and
+/// MAC, or returns `None` if it is too short to hold them.
+pub fn split_datagram(datagram: &[u8]) -> Option<(u64, &[u8], &[u8])> {
+	if datagram.len() < COUNTER_SIZE + TAG_SIZE {
+		return None;
+	}
+
+	let (counter, rest) = datagram.split_at(COUNTER_SIZE);
+	let (message, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+	Some((u64::from_be_bytes(counter.try_into().unwrap()), message, tag))
+}
+
+/// Whether the tag split from a datagram is the MAC of its direction, counter,
+/// and message under the key. Replays must be checked for separately.
+pub fn verify_datagram(key: &[u8], direction: u8, counter: u64, message: &[u8], tag: &[u8]) -> bool {
+	mac(key, direction, counter, message)
+		.verify_slice(tag)
+		.is_ok()
+}
+
+/// Starts an HMAC-SHA256 under the key.
+fn hmac(key: &[u8]) -> HmacSha256 {
+	// HMAC accepts keys of any length, so this cannot fail
+	HmacSha256::new_from_slice(key).unwrap()
+}
+
+/// Computes the MAC over a handshake label and both nonces.
+fn handshake_mac(psk: &[u8], label: &[u8], flight_nonce: &[u8], server_nonce: &[u8]) -> HmacSha256 {
+	let mut mac = hmac(psk);
+	mac.update(label);
+	mac.update(flight_nonce);
+	mac.update(server_nonce);
+	mac
+}
+
+/// Computes the MAC over a frame's or datagram's direction, counter, and payload.
+fn mac(key: &[u8], direction: u8, counter: u64, payload: &[u8]) -> HmacSha256 {
+	let mut mac = hmac(key);
+	mac.update(&[direction]);
+	mac.update(&counter.to_be_bytes());
+	mac.update(payload);
+	mac
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{net::TcpListener, thread};
+
+	const PSK: &[u8] = b"pre-shared key";
+
+	/// Connects a pair of sockets over the loopback interface.
+	fn connection() -> (TcpStream, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (server, _) = listener.accept().unwrap();
+		(client, server)
+	}
+
+	/// Frames a payload as the server would.
+	fn server_frame(key: &[u8], counter: u64, payload: &[u8]) -> Vec<u8> {
+		let tag = mac(key, SERVER_TO_FLIGHT, counter, payload)
+			.finalize()
+			.into_bytes();
+
+		let mut frame = Vec::new();
+		frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+		frame.extend_from_slice(&counter.to_be_bytes());
+		frame.extend_from_slice(payload);
+		frame.extend_from_slice(&tag);
+		frame
+	}
+
+	/// Plays the server's side of the handshake, returning the session key.
+	fn server_handshake(stream: &mut TcpStream, psk: &[u8]) -> io::Result<[u8; 32]> {
+		let mut flight_nonce = [0; NONCE_SIZE];
+		stream.read_exact(&mut flight_nonce)?;
+
+		let server_nonce = [7; NONCE_SIZE];
+		let server_proof = handshake_mac(psk, b"server", &flight_nonce, &server_nonce).finalize().into_bytes();
+		stream.write_all(&server_nonce)?;
+		stream.write_all(&server_proof)?;
+
+		let mut flight_proof = [0; TAG_SIZE];
+		stream.read_exact(&mut flight_proof)?;
+
+		handshake_mac(psk, b"flight", &flight_nonce, &server_nonce)
+			.verify_slice(&flight_proof)
+			.map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "flight computer does not know the key"))?;
+
+		Ok(handshake_mac(psk, b"session", &flight_nonce, &server_nonce).finalize().into_bytes().into())
+	}
+
+	#[test]
+	fn handshake_establishes_session() {
+		let (client, mut server) = connection();
+		let server = thread::spawn(move || server_handshake(&mut server, PSK).map(|key| (server, key)));
+
+		let (mut reader, mut writer) = authenticate(client, PSK).unwrap();
+		let (mut server, key) = server.join().unwrap().unwrap();
+
+		writer.send(b"report").unwrap();
+
+		let mut frame = vec![0; 12 + 6 + TAG_SIZE];
+		server.read_exact(&mut frame).unwrap();
+		assert_eq!(&frame[12..18], b"report");
+		assert!(mac(&key, FLIGHT_TO_SERVER, 0, b"report").verify_slice(&frame[18..]).is_ok());
+
+		server.write_all(&server_frame(&key, 0, b"command")).unwrap();
+		assert_eq!(reader.receive().unwrap(), b"command");
+	}
+
+	#[test]
+	fn handshake_rejects_wrong_key() {
+		let (client, mut server) = connection();
+		let server = thread::spawn(move || server_handshake(&mut server, b"wrong key"));
+
+		let error = authenticate(client, PSK).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+		assert!(server.join().unwrap().is_err());
+	}
+
+	#[test]
+	fn tampered_and_replayed_frames_are_rejected() {
+		let (client, mut server) = connection();
+		let key = [1; 32];
+		let mut reader = FrameReader { stream: client, key, next_counter: 0 };
+
+		let mut tampered = server_frame(&key, 0, b"open");
+		tampered[12] ^= 1;
+		server.write_all(&tampered).unwrap();
+		assert!(matches!(reader.receive(), Err(FrameError::Unauthenticated)));
+
+		// a frame meant for the server cannot be reflected back
+		let mut reflected = server_frame(&key, 0, b"open");
+		let tag = mac(&key, FLIGHT_TO_SERVER, 0, b"open").finalize().into_bytes();
+		reflected[16..].copy_from_slice(&tag);
+		server.write_all(&reflected).unwrap();
+		assert!(matches!(reader.receive(), Err(FrameError::Unauthenticated)));
+
+		server.write_all(&server_frame(&key, 5, b"open")).unwrap();
+		assert_eq!(reader.receive().unwrap(), b"open");
+
+		server.write_all(&server_frame(&key, 5, b"open")).unwrap();
+		assert!(matches!(reader.receive(), Err(FrameError::Unauthenticated)));
+
+		server.write_all(&server_frame(&key, 4, b"open")).unwrap();
+		assert!(matches!(reader.receive(), Err(FrameError::Unauthenticated)));
+
+		server.write_all(&server_frame(&key, 6, b"close")).unwrap();
+		assert_eq!(reader.receive().unwrap(), b"close");
+	}
+
+	#[test]
+	fn oversized_frame_is_rejected() {
+		let (client, mut server) = connection();
+		let mut reader = FrameReader { stream: client, key: [1; 32], next_counter: 0 };
+
+		let mut header = Vec::new();
+		header.extend_from_slice(&(CONTROL_MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+		header.extend_from_slice(&0u64.to_be_bytes());
+		server.write_all(&header).unwrap();
+
+		assert!(matches!(reader.receive(), Err(FrameError::Io(_))));
+	}
+
+	#[test]
+	fn stalled_server_drops_connection() {
+		let (client, server) = connection();
+		client.set_write_timeout(Some(CONTROL_WRITE_TIMEOUT)).unwrap();
+		let mut writer = FrameWriter { stream: client, key: [1; 32], counter: 0 };
+
+		// the server never reads, so the socket buffers eventually fill
+		let payload = vec![0; 1 << 20];
+		let error = (0..1_000).find_map(|_| writer.send(&payload).err());
+		assert!(error.is_some());
+
+		// shut down, so later frames fail at once rather than after another timeout
+		assert!(writer.send(b"report").is_err());
+		drop(server);
+	}
+}
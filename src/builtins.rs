@@ -0,0 +1,217 @@
+use common::comm::ValveState;
+use pyo3::{exceptions::{PyRuntimeError, PyValueError}, types::{PyCFunction, PyDict, PyTuple}, PyResult, Python};
+use std::{thread, time::Duration};
+use crate::{handler::{self, ArmingError, InterlockError}, scheduler::{self, Schedule, ScheduledActuation}, state::SharedState, CommandSender};
+
+/// Makes flight-specific functions available to every sequence as Python builtins.
+///
+/// These complement the device actions defined in `common`, which cannot be
+/// extended from the flight computer alone. Must be called after `sequence::initialize`.
+pub fn register(shared: &SharedState) -> PyResult<()> {
+	let valve_mismatches = shared.valve_mismatches.clone();
+	let shared = shared.clone();
+
+	Python::with_gil(|py| {
+		let builtins = py.import("builtins")?;
+
+		let valve_mismatched = PyCFunction::new_closure(
+			py,
+			Some("valve_mismatched\0"),
+			Some("Returns whether the named valve has failed to reach its commanded state within its response time.\0"),
+			move |args: &PyTuple, _: Option<&PyDict>| -> PyResult<bool> {
+				let name = args.get_item(0)?.extract::<String>()?;
+				Ok(valve_mismatches.lock().unwrap().contains(&name))
+			},
+		)?;
+
+		let actuate_shared = shared.clone();
+
+		let actuate_valve = PyCFunction::new_closure(
+			py,
+			Some("actuate_valve\0"),
+			Some("Commands the named valve 'open' or 'closed', raising ArmingError if the vehicle is not armed or InterlockError if the command would violate an interlock.\0"),
+			move |args: &PyTuple, _: Option<&PyDict>| -> PyResult<()> {
+				let name = args.get_item(0)?.extract::<String>()?;
+				let state = args.get_item(1)?.extract::<String>()?;
+				actuate_valve(&actuate_shared, &name, &state)
+			},
+		)?;
+
+		let schedule_valves = PyCFunction::new_closure(
+			py,
+			Some("schedule_valves\0"),
+			Some("Actuates valves at precise offsets, given as a list of (seconds, valve, 'open' or 'closed'), and returns (valve, state, scheduled, actual) for each actuation made, where actual is None if the command never reached its board.\0"),
+			move |args: &PyTuple, _: Option<&PyDict>| -> PyResult<ScheduleTimings> {
+				let name = args.get_item(0)?.extract::<String>()?;
+				let actuations = args.get_item(1)?.extract::<Vec<(f64, String, String)>>()?;
+				schedule_valves(&shared, name, actuations)
+			},
+		)?;
+
+		builtins.setattr("valve_mismatched", valve_mismatched)?;
+		builtins.setattr("actuate_valve", actuate_valve)?;
+		builtins.setattr("schedule_valves", schedule_valves)?;
+		builtins.setattr("InterlockError", py.get_type::<InterlockError>())?;
+		builtins.setattr("ArmingError", py.get_type::<ArmingError>())?;
+		Ok(())
+	})
+}
+
+/// Commands a valve on behalf of the calling sequence, raising the reason if it is rejected.
+///
+/// Unlike commanding a valve through its device, this lets a sequence catch the
+/// rejection and decide what to do instead.
+fn actuate_valve(shared: &SharedState, name: &str, state: &str) -> PyResult<()> {
+	let sequence = running_sequence(shared, "actuate_valve")?;
+	let command_tx = command_tx(shared)?;
+	let state = parse_state(name, state)?;
+
+	handler::actuate_for_sequence(shared, &sequence, name, state, &command_tx)?;
+	Ok(())
+}
+
+/// The `(valve, state, scheduled, actual)` of each actuation made by `schedule_valves`.
+type ScheduleTimings = Vec<(String, String, f64, Option<f64>)>;
+
+/// Runs a valve schedule on behalf of the calling sequence, blocking until it completes.
+///
+/// The schedule runs on its own thread so that its timing is unaffected by the
+/// GIL, which is released while waiting for it.
+fn schedule_valves(shared: &SharedState, name: String, actuations: Vec<(f64, String, String)>) -> PyResult<ScheduleTimings> {
+	let sequence = running_sequence(shared, "schedule_valves")?;
+	let command_tx = command_tx(shared)?;
+
+	let actuations = actuations
+		.into_iter()
+		.map(|(offset, valve, state)| {
+			let state = parse_state(&valve, &state)?;
+
+			let offset = Duration::try_from_secs_f64(offset)
+				.map_err(|error| PyValueError::new_err(format!("invalid offset for valve '{valve}': {error}")))?;
+
+			Ok(ScheduledActuation { offset, valve, state })
+		})
+		.collect::<PyResult<Vec<_>>>()?;
+
+	let schedule = Schedule { name, actuations };
+	let shared = shared.clone();
+
+	let timer = thread::Builder::new()
+		.name(format!("schedule-{}", schedule.name))
+		.spawn(move || scheduler::run(&shared, &command_tx, schedule, Some(&sequence)))
+		.map_err(|error| PyRuntimeError::new_err(format!("failed to spawn schedule thread: {error}")))?;
+
+	let timings = Python::with_gil(|py| py.allow_threads(|| timer.join()))
+		.map_err(|_| PyRuntimeError::new_err("schedule thread panicked"))?;
+
+	let timings = timings
+		.into_iter()
+		.map(|timing| {
+			let state = if timing.state == ValveState::Open { "open" } else { "closed" };
+			(timing.valve, state.to_owned(), timing.scheduled.as_secs_f64(), timing.actual.map(|actual| actual.as_secs_f64()))
+		})
+		.collect();
+
+	Ok(timings)
+}
+
+/// The name of the sequence running on the calling thread, which `function` requires.
+fn running_sequence(shared: &SharedState, function: &str) -> PyResult<String> {
+	shared.sequences
+		.lock()
+		.unwrap()
+		.get_by_right(&thread::current().id())
+		.cloned()
+		.ok_or_else(|| PyRuntimeError::new_err(format!("{function} may only be called from a running sequence")))
+}
+
+fn command_tx(shared: &SharedState) -> PyResult<CommandSender> {
+	shared.command_tx
+		.lock()
+		.unwrap()
+		.clone()
+		.ok_or_else(|| PyRuntimeError::new_err("the switchboard has not started"))
+}
+
+fn parse_state(valve: &str, state: &str) -> PyResult<ValveState> {
+	match state {
+		"open" => Ok(ValveState::Open),
+		"closed" => Ok(ValveState::Closed),
+		_ => Err(PyValueError::new_err(format!("invalid state '{state}' for valve '{valve}', expected 'open' or 'closed'"))),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::{Computer, NodeMapping, SensorType};
+	use std::sync::mpsc;
+	use crate::{arming::ArmState, interlock::{Interlock, InterlockCondition}, persistence::PersistedConfiguration, settings::Settings};
+
+	/// Shared state with a single valve, `fuel`, which may not be opened while
+	/// `tank` has no reading, registering the calling thread as a running sequence.
+	fn shared_state() -> SharedState {
+		let persisted = PersistedConfiguration {
+			mappings: vec![NodeMapping {
+				text_id: "fuel".to_owned(),
+				board_id: "sam-01".to_owned(),
+				sensor_type: SensorType::Valve,
+				channel: 1,
+				computer: Computer::Flight,
+				max: None,
+				min: None,
+				calibrated_offset: 0.0,
+				powered_threshold: Some(0.1),
+				normally_closed: Some(true),
+			}],
+			interlocks: vec![Interlock {
+				name: "tank_pressure".to_owned(),
+				valve: "fuel".to_owned(),
+				state: ValveState::Open,
+				condition: InterlockCondition::SensorAbove { sensor: "tank".to_owned(), limit: 100.0 },
+			}],
+			..Default::default()
+		};
+
+		let shared = SharedState::new(persisted, Settings::flight(), mpsc::channel().0);
+		*shared.command_tx.lock().unwrap() = Some(mpsc::channel().0);
+		shared.sequences.lock().unwrap().insert("test".to_owned(), thread::current().id());
+		shared
+	}
+
+	/// Runs `actuate_valve` from Python, returning the name of the exception it raised, if any.
+	fn raised(shared: &SharedState, valve: &str, state: &str) -> Option<String> {
+		register(shared).unwrap();
+
+		Python::with_gil(|py| {
+			let locals = PyDict::new(py);
+			locals.set_item("valve", valve).unwrap();
+			locals.set_item("state", state).unwrap();
+
+			let script = "\
+raised = None
+try:
+	actuate_valve(valve, state)
+except InterlockError:
+	raised = 'InterlockError'
+except ArmingError:
+	raised = 'ArmingError'
+";
+
+			py.run(script, None, Some(locals)).unwrap();
+			locals.get_item("raised").unwrap().unwrap().extract::<Option<String>>().unwrap()
+		})
+	}
+
+	#[test]
+	fn rejected_actuations_raise_catchable_exceptions() {
+		pyo3::prepare_freethreaded_python();
+		let shared = shared_state();
+
+		assert_eq!(raised(&shared, "fuel", "open").as_deref(), Some("ArmingError"));
+
+		shared.arming.lock().unwrap().state = ArmState::Armed;
+		assert_eq!(raised(&shared, "fuel", "open").as_deref(), Some("InterlockError"));
+		assert_eq!(raised(&shared, "fuel", "closed"), None);
+	}
+}
@@ -0,0 +1,390 @@
+use common::{comm::Computer, sequence};
+use jeflog::{fail, pass, task, warn};
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, net::{SocketAddr, TcpStream}, thread, time::Duration};
+use crate::{abort::AbortCause, auth::{self, FrameError, FrameReader}, discovery::{self, Backoff, DiscoveryMethod, DiscoverySettings}, event::{self, Event, EventSender}, handler, protocol::{self, FlightReport}, state::SharedState, CONTROL_KEY_PATH, DEFAULT_HEARTBEAT_TIMEOUT};
+
+/// Which of the redundant control servers a connection is to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ServerRole {
+	Primary,
+	Backup,
+}
+
+impl ServerRole {
+	/// The role of the other server.
+	pub fn other(self) -> Self {
+		match self {
+			Self::Primary => Self::Backup,
+			Self::Backup => Self::Primary,
+		}
+	}
+}
+
+impl fmt::Display for ServerRole {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Primary => write!(f, "primary"),
+			Self::Backup => write!(f, "backup"),
+		}
+	}
+}
+
+/// How the control connections are monitored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ControlSettings {
+	/// How long a server may go without sending anything, heartbeats included,
+	/// before its connection is considered lost, or `None` to wait indefinitely,
+	/// written as `0`. Without a timeout, a server which stops responding without
+	/// closing the connection is never considered lost, so the loss-of-ground
+	/// policy is never applied.
+	#[serde(with = "optional_milliseconds")]
+	pub heartbeat_timeout: Option<Duration>,
+
+	/// What to do once the connection to every server has been lost.
+	pub loss_of_ground: LossOfGroundPolicy,
+}
+
+impl Default for ControlSettings {
+	fn default() -> Self {
+		ControlSettings {
+			heartbeat_timeout: Some(DEFAULT_HEARTBEAT_TIMEOUT),
+			loss_of_ground: LossOfGroundPolicy::Continue,
+		}
+	}
+}
+
+/// What the flight computer does once it has lost the connection to every server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LossOfGroundPolicy {
+	/// Carry on with whatever is running.
+	Continue,
+
+	/// Stop every running sequence, leaving valves as they are.
+	Hold,
+
+	/// Stop every running sequence and run the named safing sequence, which
+	/// must have been sent by the server beforehand. Aborts if it was not.
+	RunSequence(String),
+
+	/// Abort, as if commanded by the operator.
+	Abort,
+}
+
+impl fmt::Display for LossOfGroundPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Continue => write!(f, "continue"),
+			Self::Hold => write!(f, "hold"),
+			Self::RunSequence(name) => write!(f, "run sequence '{name}'"),
+			Self::Abort => write!(f, "abort"),
+		}
+	}
+}
+
+impl ControlSettings {
+	/// Warns if the loss-of-ground policy can never be applied to a server which
+	/// stops responding without closing the connection.
+	pub fn warn_if_unmonitored(&self) {
+		if self.heartbeat_timeout.is_none() && !matches!(self.loss_of_ground, LossOfGroundPolicy::Continue) {
+			warn!("The loss-of-ground policy is to {}, but there is no heartbeat timeout, so it only applies once a server closes its connection.", self.loss_of_ground);
+		}
+	}
+}
+
+/// Serializes an optional `Duration` as a whole number of milliseconds, with
+/// `0` standing for `None` since TOML has no null.
+mod optional_milliseconds {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::time::Duration;
+
+	pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_u64(duration.map_or(0, |duration| duration.as_millis() as u64))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+		u64::deserialize(deserializer).map(|millis| (millis != 0).then(|| Duration::from_millis(millis)))
+	}
+}
+
+/// Something that happened on a control connection, passed from the thread
+/// maintaining that connection to the main loop.
+#[derive(Debug)]
+pub enum ControlEvent {
+	/// The server was found, authenticated, and sent the flight computer's identity.
+	Connected(ServerRole),
+
+	/// An authenticated frame was received from the server.
+	Frame(ServerRole, Vec<u8>),
+
+	/// The connection to the server was lost. The thread is already looking for it again.
+	Disconnected(ServerRole),
+}
+
+/// Constructs a closure which keeps a connection to the server with the given
+/// role, rediscovering it whenever the connection drops, and passes everything
+/// that happens on the connection along as `ControlEvent`s.
+pub fn maintain_connection(shared: &SharedState, role: ServerRole, settings: DiscoverySettings, events: EventSender) -> impl FnOnce() {
+	let shared = shared.clone();
+
+	move || {
+		loop {
+			let mut reader = discover(&shared, role, &settings);
+
+			let heartbeat_timeout = shared.settings.lock().unwrap().control.heartbeat_timeout;
+
+			if let Err(error) = reader.set_read_timeout(heartbeat_timeout) {
+				warn!("Failed to set the heartbeat timeout for the {role} server, so it will not be enforced: {error}");
+			}
+
+			if events.send(Event::Control(ControlEvent::Connected(role))).is_err() {
+				return;
+			}
+
+			loop {
+				match reader.receive() {
+					Ok(frame) => {
+						if events.send(Event::Control(ControlEvent::Frame(role, frame))).is_err() {
+							return;
+						}
+					},
+					Err(FrameError::Unauthenticated) => {
+						warn!("Rejected control message from the {role} server which failed authentication.");
+					},
+					// the server closed the connection
+					Err(FrameError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+					Err(FrameError::Io(error)) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+						fail!("The {role} server has not sent a heartbeat within {heartbeat_timeout:?}. Dropping connection.");
+						break;
+					},
+					Err(error) => {
+						fail!("Failed to read from the {role} server: {error}. Dropping connection.");
+						break;
+					},
+				}
+			}
+
+			shared.control_sockets.lock().unwrap().remove(&role);
+			shared.server_addresses.lock().unwrap().remove(&role);
+
+			if events.send(Event::Control(ControlEvent::Disconnected(role))).is_err() {
+				return;
+			}
+		}
+	}
+}
+
+/// Blocks until the server is found and connected to, backing off between attempts.
+fn discover(shared: &SharedState, role: ServerRole, settings: &DiscoverySettings) -> FrameReader {
+	let mut backoff = Backoff::new(settings);
+
+	loop {
+		task!("Locating {role} control server.");
+
+		// read on every attempt so that the key can be replaced without a restart
+		match auth::load_key() {
+			Ok(psk) => {
+				// a server already connected in the other role is never this role's server too
+				let excluded = shared.server_addresses
+					.lock()
+					.unwrap()
+					.iter()
+					.filter(|(other, _)| **other != role)
+					.map(|(_, address)| *address)
+					.collect::<Vec<_>>();
+
+				for (address, method) in discovery::locate(&shared.beacon_listeners, settings, &excluded) {
+					task!("Attempting to connect to \x1b[1m{address}\x1b[0m, found by {method}.");
+
+					if let Some(reader) = connect_to_server(shared, role, address, settings, &psk, &method) {
+						pass!("Found {role} control server at \x1b[1m{address}\x1b[0m by {method}.");
+						return reader;
+					}
+				}
+
+				fail!("Failed to locate {role} control server at any potential address.");
+			},
+			Err(error) => {
+				fail!("Failed to load control server key from {CONTROL_KEY_PATH}: {error}. Cannot authenticate the control server.");
+			},
+		}
+
+		backoff.wait();
+	}
+}
+
+/// Connects to, authenticates, and identifies the flight computer to a potential
+/// control server, returning the receiving half of the connection if successful.
+fn connect_to_server(shared: &SharedState, role: ServerRole, address: SocketAddr, settings: &DiscoverySettings, psk: &[u8], method: &DiscoveryMethod) -> Option<FrameReader> {
+	let stream = match TcpStream::connect_timeout(&address, settings.connect_timeout) {
+		Ok(stream) => stream,
+		Err(error) => {
+			fail!("Failed to connect to \x1b[1m{address}\x1b[0m: {error}");
+			return None;
+		},
+	};
+
+	pass!("Successfully connected to \x1b[1m{address}\x1b[0m.");
+
+	// nothing is sent to or accepted from the server until it proves it knows the key
+	let (reader, mut writer) = match auth::authenticate(stream, psk) {
+		Ok(halves) => halves,
+		Err(error) => {
+			fail!("Failed to authenticate \x1b[1m{address}\x1b[0m: {error}");
+			return None;
+		},
+	};
+
+	// buffer containing the serialized identity message to be sent to the control server
+	let mut identity = [0; Computer::POSTCARD_MAX_SIZE];
+
+	// the role is configured rather than derived from the hostname, since it decides which boards are owned
+	let serialized = postcard::to_slice(&shared.settings.lock().unwrap().role.computer, &mut identity).map(|_| ());
+
+	if let Err(error) = serialized {
+		fail!("Failed to serialize Computer: {error}");
+		return None;
+	}
+
+	if let Err(error) = writer.send(&identity) {
+		warn!("Failed to send identity message to control server: {error}");
+		return None;
+	}
+
+	// sent to this server alone, since the other server has already received them
+	let versions = *shared.config_versions.lock().unwrap();
+	protocol::send_report_to(&mut writer, &FlightReport::Configuration(versions));
+	protocol::send_report_to(&mut writer, &FlightReport::ServerDiscovered(method.clone()));
+
+	shared.control_sockets.lock().unwrap().insert(role, writer);
+	shared.server_addresses.lock().unwrap().insert(role, address.ip());
+
+	Some(reader)
+}
+
+/// Gives command authority to a newly connected server if no server holds it.
+///
+/// Authority is never taken from a connected server automatically, so a primary
+/// reconnecting during a test does not interrupt the backup's control.
+pub fn server_connected(shared: &SharedState, role: ServerRole) {
+	let authority = *shared.authority.lock().unwrap();
+
+	match authority {
+		None => set_authority(shared, Some(role)),
+		// the new server would not otherwise learn who holds authority
+		Some(holder) => protocol::send_report(&shared.control_sockets, &FlightReport::Authority(Some(holder))),
+	}
+}
+
+/// Fails authority over to the other server if the disconnected server held it.
+pub fn server_disconnected(shared: &SharedState, role: ServerRole) {
+	if *shared.authority.lock().unwrap() != Some(role) {
+		return;
+	}
+
+	if shared.control_sockets.lock().unwrap().contains_key(&role.other()) {
+		fail!("Lost connection to the {role} server while it held command authority. Failing over to the {} server.", role.other());
+		set_authority(shared, Some(role.other()));
+	} else {
+		fail!("Lost connection to the {role} server while it held command authority, and no other server is connected.");
+		set_authority(shared, None);
+	}
+}
+
+/// Hands authority from the server holding it to the other server, if connected.
+pub fn hand_over(shared: &SharedState, from: ServerRole) {
+	if !shared.control_sockets.lock().unwrap().contains_key(&from.other()) {
+		fail!("Refusing to hand over command authority because the {} server is not connected.", from.other());
+		return;
+	}
+
+	set_authority(shared, Some(from.other()));
+}
+
+/// Sets which server holds command authority, reporting it to every server.
+fn set_authority(shared: &SharedState, authority: Option<ServerRole>) {
+	*shared.authority.lock().unwrap() = authority;
+
+	match authority {
+		Some(role) => pass!("The {role} server now holds command authority."),
+		None => warn!("No server holds command authority."),
+	}
+
+	protocol::send_report(&shared.control_sockets, &FlightReport::Authority(authority));
+}
+
+/// Whether the named sequence is the safing sequence run upon losing the ground.
+pub fn is_safing_sequence(shared: &SharedState, name: &str) -> bool {
+	safing_sequence_name(shared).is_some_and(|safing| safing == name)
+}
+
+/// The name of the sequence run upon losing the ground, if the loss-of-ground policy runs one.
+pub fn safing_sequence_name(shared: &SharedState) -> Option<String> {
+	match &shared.settings.lock().unwrap().control.loss_of_ground {
+		LossOfGroundPolicy::RunSequence(safing) => Some(safing.clone()),
+		_ => None,
+	}
+}
+
+/// Applies the loss-of-ground policy, once the connection to every server has been lost.
+pub fn lose_ground(shared: &SharedState) {
+	let policy = shared.settings.lock().unwrap().control.loss_of_ground.clone();
+	fail!("Lost connection to every control server. Applying loss-of-ground policy: {policy}.");
+
+	match policy {
+		LossOfGroundPolicy::Continue => {},
+		LossOfGroundPolicy::Hold => {
+			// sequences stop upon their next device action once unregistered
+			shared.sequences.lock().unwrap().clear();
+			warn!("Stopped every running sequence. Holding until a control server reconnects.");
+		},
+		LossOfGroundPolicy::RunSequence(name) => run_safing_sequence(shared, &name),
+		LossOfGroundPolicy::Abort => handler::abort(shared, AbortCause::LossOfGround),
+	}
+}
+
+/// Stops every running sequence and runs the safing sequence in their place,
+/// aborting instead if the safing sequence was never received.
+fn run_safing_sequence(shared: &SharedState, name: &str) {
+	if shared.abort.lock().unwrap().aborted {
+		warn!("Not running safing sequence '{name}' because the flight computer has already aborted.");
+		return;
+	}
+
+	let safing_sequence = shared.safing_sequence
+		.lock()
+		.unwrap()
+		.clone()
+		.filter(|sequence| sequence.name == name);
+
+	let Some(sequence) = safing_sequence else {
+		fail!("Safing sequence '{name}' was never received from the server. Aborting instead.");
+		handler::abort(shared, AbortCause::LossOfGround);
+		return;
+	};
+
+	let mut sequences = shared.sequences.lock().unwrap();
+	sequences.clear();
+
+	let thread_shared = shared.clone();
+	let finished_name = name.to_owned();
+
+	let thread_id = thread::spawn(move || {
+		sequence::run(sequence);
+
+		thread_shared.sequences
+			.lock()
+			.unwrap()
+			.remove_by_right(&thread::current().id());
+
+		event::notify(&thread_shared, Event::SequenceFinished(finished_name));
+	})
+		.thread()
+		.id();
+
+	sequences.insert(name.to_owned(), thread_id);
+	pass!("Started safing sequence '{name}'.");
+}
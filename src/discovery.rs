@@ -0,0 +1,296 @@
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::{hash_map::Entry, HashMap}, fmt, io, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use crate::{auth, settings::milliseconds, BEACON_POLL_PERIOD, SERVO_PORT};
+
+/// How the flight computer looks for the control server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiscoverySettings {
+	/// Hosts to try, in order, after any servers heard from by beacon. Each is
+	/// either `host` or `host:port`, with the port defaulting to `SERVO_PORT`.
+	pub candidates: Vec<String>,
+
+	/// UDP port on which the server broadcasts beacons, or `None` to not listen for them.
+	pub beacon_port: Option<u16>,
+
+	/// Multicast group to join when listening for beacons, if the server
+	/// multicasts rather than broadcasts them.
+	pub multicast_group: Option<Ipv4Addr>,
+
+	/// How recently a server must have sent a beacon to be tried, and how long to
+	/// wait for one on each attempt if none has.
+	#[serde(with = "milliseconds")]
+	pub beacon_wait: Duration,
+
+	/// How long to wait for each candidate to accept a connection.
+	#[serde(with = "milliseconds")]
+	pub connect_timeout: Duration,
+
+	/// How long to wait after the first failed attempt. Doubles after each
+	/// further failure, up to `max_backoff`.
+	#[serde(with = "milliseconds")]
+	pub initial_backoff: Duration,
+
+	#[serde(with = "milliseconds")]
+	pub max_backoff: Duration,
+}
+
+impl Default for DiscoverySettings {
+	fn default() -> Self {
+		DiscoverySettings {
+			candidates: vec!["server-01.local".to_owned(), "server-02.local".to_owned(), "localhost".to_owned()],
+			beacon_port: None,
+			multicast_group: None,
+			beacon_wait: Duration::from_secs(1),
+			connect_timeout: Duration::from_secs(1),
+			initial_backoff: Duration::from_millis(500),
+			max_backoff: Duration::from_secs(30),
+		}
+	}
+}
+
+/// Announcement sent periodically by the control server over UDP.
+///
+/// Beacons are not authenticated, since the control connection they lead to is.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ServerBeacon {
+	/// The port on which the server accepts control connections.
+	pub port: u16,
+}
+
+/// How a server address was found.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DiscoveryMethod {
+	/// A beacon was received from the server.
+	Beacon,
+
+	/// The server was one of the static candidates, given as configured.
+	Candidate(String),
+}
+
+impl fmt::Display for DiscoveryMethod {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Beacon => write!(f, "beacon"),
+			Self::Candidate(host) => write!(f, "static candidate '{host}'"),
+		}
+	}
+}
+
+/// A socket listening for beacons on a single port.
+///
+/// Only one socket can be bound to the port, so the listener is shared by the
+/// discovery of every server beaconing on it, such as the primary and the backup.
+#[derive(Debug)]
+pub struct BeaconListener {
+	socket: UdpSocket,
+
+	/// Multicast groups the socket has joined.
+	groups: Vec<Ipv4Addr>,
+
+	/// Every server heard from, and when it was last heard from.
+	heard: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+impl BeaconListener {
+	/// Binds the port and starts a thread recording every beacon received on it.
+	fn start(port: u16) -> io::Result<Self> {
+		let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+		let receiver = socket.try_clone()?;
+		let heard = Arc::new(Mutex::new(HashMap::new()));
+		let thread_heard = heard.clone();
+
+		thread::Builder::new()
+			.name(format!("beacons-{port}"))
+			.spawn(move || receive_beacons(receiver, thread_heard))?;
+
+		Ok(BeaconListener { socket, groups: Vec::new(), heard })
+	}
+
+	fn join(&mut self, group: Ipv4Addr) {
+		if self.groups.contains(&group) {
+			return;
+		}
+
+		match self.socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED) {
+			Ok(()) => self.groups.push(group),
+			Err(error) => fail!("Failed to join multicast group {group} for server beacons: {error}"),
+		}
+	}
+}
+
+/// Finds every address at which the server might be, servers heard from by
+/// beacon first, followed by the static candidates in order.
+///
+/// Addresses at any of the `excluded` hosts, such as that of a server already
+/// connected to in another role, are left out.
+pub fn locate(listeners: &Mutex<HashMap<u16, BeaconListener>>, settings: &DiscoverySettings, excluded: &[IpAddr]) -> Vec<(SocketAddr, DiscoveryMethod)> {
+	let mut addresses = Vec::new();
+
+	if let Some(port) = settings.beacon_port {
+		for address in heard_servers(listeners, port, settings.multicast_group, settings.beacon_wait) {
+			if !addresses.iter().any(|(a, _)| *a == address) {
+				addresses.push((address, DiscoveryMethod::Beacon));
+			}
+		}
+	}
+
+	for candidate in &settings.candidates {
+		let resolved = if candidate.contains(':') {
+			candidate.to_socket_addrs()
+		} else {
+			(candidate.as_str(), SERVO_PORT).to_socket_addrs()
+		};
+
+		match resolved {
+			Ok(resolved) => {
+				for address in resolved {
+					addresses.push((address, DiscoveryMethod::Candidate(candidate.clone())));
+				}
+			},
+			Err(error) => warn!("Failed to resolve \x1b[1m{candidate}\x1b[0m: {error}"),
+		}
+	}
+
+	addresses.retain(|(address, _)| !excluded.contains(&address.ip()));
+	addresses
+}
+
+/// Returns the servers heard from by beacon on the port within the last `wait`,
+/// most recently heard first, waiting up to `wait` for one if there are none.
+///
+/// The port is listened on from the first call onwards, so that beacons are
+/// still heard while no discovery is waiting for them.
+fn heard_servers(listeners: &Mutex<HashMap<u16, BeaconListener>>, port: u16, multicast_group: Option<Ipv4Addr>, wait: Duration) -> Vec<SocketAddr> {
+	let mut listeners = listeners.lock().unwrap();
+
+	let listener = match listeners.entry(port) {
+		Entry::Occupied(entry) => entry.into_mut(),
+		Entry::Vacant(entry) => match BeaconListener::start(port) {
+			Ok(listener) => entry.insert(listener),
+			Err(error) => {
+				fail!("Failed to listen for server beacons on port {port}: {error}");
+				return Vec::new();
+			},
+		},
+	};
+
+	if let Some(group) = multicast_group {
+		listener.join(group);
+	}
+
+	let heard = listener.heard.clone();
+	drop(listeners);
+
+	let deadline = Instant::now() + wait;
+
+	loop {
+		let now = Instant::now();
+
+		let mut servers = heard
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|(_, at)| now.saturating_duration_since(**at) <= wait)
+			.map(|(server, at)| (*server, *at))
+			.collect::<Vec<_>>();
+
+		if !servers.is_empty() || now >= deadline {
+			servers.sort_by_key(|(_, at)| Reverse(*at));
+			return servers.into_iter().map(|(server, _)| server).collect();
+		}
+
+		thread::sleep(BEACON_POLL_PERIOD.min(deadline - now));
+	}
+}
+
+/// Records when every server beaconing to the socket was last heard from.
+fn receive_beacons(socket: UdpSocket, heard: Arc<Mutex<HashMap<SocketAddr, Instant>>>) {
+	let mut buffer = [0; 64];
+
+	loop {
+		let (size, sender) = match socket.recv_from(&mut buffer) {
+			Ok(received) => received,
+			Err(error) => {
+				fail!("Failed to receive server beacon: {error}");
+				thread::sleep(BEACON_POLL_PERIOD);
+				continue;
+			},
+		};
+
+		match postcard::from_bytes::<ServerBeacon>(&buffer[..size]) {
+			Ok(beacon) => {
+				let server = SocketAddr::new(sender.ip(), beacon.port);
+
+				if heard.lock().unwrap().insert(server, Instant::now()).is_none() {
+					pass!("Heard beacon from server at \x1b[1m{server}\x1b[0m.");
+				}
+			},
+			Err(error) => warn!("Ignoring invalid beacon from {sender}: {error}"),
+		}
+	}
+}
+
+/// Exponentially increasing delay between discovery attempts, with jitter so
+/// that several flight computers restarting together do not retry in lockstep.
+#[derive(Debug)]
+pub struct Backoff {
+	next: Duration,
+	max: Duration,
+}
+
+impl Backoff {
+	pub fn new(settings: &DiscoverySettings) -> Self {
+		Backoff { next: settings.initial_backoff, max: settings.max_backoff }
+	}
+
+	/// Sleeps for between half and all of the current delay, then doubles it.
+	pub fn wait(&mut self) {
+		// without randomness, this falls back to waiting for the whole delay
+		let random = auth::random_u64().unwrap_or(u64::MAX);
+		let fraction = 0.5 + (random as f64 / u64::MAX as f64) / 2.0;
+		let delay = self.next.mul_f64(fraction);
+
+		warn!("Retrying server discovery in {delay:?}.");
+		thread::sleep(delay);
+
+		self.next = (self.next * 2).min(self.max);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A port which was free a moment ago.
+	fn free_port() -> u16 {
+		UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+	}
+
+	#[test]
+	fn primary_and_backup_share_beacon_listener() {
+		let listeners = Mutex::new(HashMap::new());
+		let port = free_port();
+		let wait = Duration::from_millis(500);
+
+		let settings = DiscoverySettings { candidates: Vec::new(), beacon_port: Some(port), beacon_wait: wait, ..Default::default() };
+
+		// nothing has beaconed yet, so this waits out the whole wait
+		assert!(locate(&listeners, &settings, &[]).is_empty());
+
+		let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let beacon = postcard::to_allocvec(&ServerBeacon { port: 5025 }).unwrap();
+		server.send_to(&beacon, (Ipv4Addr::LOCALHOST, port)).unwrap();
+
+		let expected = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5025);
+		let located = locate(&listeners, &settings, &[]);
+		assert_eq!(located.len(), 1);
+		assert_eq!(located[0].0, expected);
+
+		// the backup hears the same beacon, but leaves out the server the primary is connected to
+		assert_eq!(locate(&listeners, &settings, &[]).len(), 1);
+		assert!(locate(&listeners, &settings, &[expected.ip()]).is_empty());
+		assert_eq!(listeners.lock().unwrap().len(), 1);
+	}
+}
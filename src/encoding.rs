@@ -0,0 +1,587 @@
+use common::comm::{CompositeValveState, Measurement, VehicleState};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashMap, fmt, mem};
+
+/// How a telemetry stream is encoded.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TelemetryEncoding {
+	/// The whole `VehicleState` in a `TelemetryEnvelope`, postcard-encoded into a single datagram.
+	Full,
+
+	/// `TelemetryEnvelope`s carrying only the values which changed since the previous
+	/// frame, fragmented into `Fragment` datagrams.
+	Delta {
+		/// How many deltas are sent between keyframes, so that a receiver which
+		/// missed a frame can resynchronize.
+		keyframe_interval: u32,
+
+		/// Whether every frame is compressed with LZ4 before being fragmented.
+		compress: bool,
+	},
+}
+
+/// A frame of telemetry, along with what the server needs to detect lost,
+/// reordered, and stale frames.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TelemetryEnvelope<'a> {
+	/// Counts the frames sent on the stream, starting from zero, so that a gap
+	/// means frames were lost.
+	pub counter: u64,
+
+	/// When the frame was taken from the vehicle state, in seconds since the
+	/// UNIX epoch by the flight computer's clock.
+	pub timestamp: f64,
+
+	pub frame: TelemetryFrame<'a>,
+}
+
+/// A frame of telemetry.
+///
+/// Acquisition times are when each reading was sampled by its board, converted
+/// to the flight computer's clock like the envelope's timestamp, and are keyed
+/// by the same names as the readings.
+///
+/// In keyframes and deltas, valves, sensors, and acquisition times are
+/// identified by their index in the names sent with the most recent keyframe,
+/// rather than by name.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TelemetryFrame<'a> {
+	/// The whole vehicle state, as sent by a `Full` stream.
+	Full {
+		state: Cow<'a, VehicleState>,
+		acquired: Cow<'a, HashMap<String, f64>>,
+	},
+
+	/// Every valve, sensor, and acquisition time, in the order of their names.
+	Keyframe {
+		valve_names: Cow<'a, [String]>,
+		sensor_names: Cow<'a, [String]>,
+		acquired_names: Cow<'a, [String]>,
+		valves: Cow<'a, [CompositeValveState]>,
+		sensors: Cow<'a, [Measurement]>,
+		acquired: Cow<'a, [f64]>,
+	},
+
+	/// The valves, sensors, and acquisition times which changed since the frame
+	/// with the previous counter, which must be applied first.
+	Delta {
+		valves: Cow<'a, [(u16, CompositeValveState)]>,
+		sensors: Cow<'a, [(u16, Measurement)]>,
+		acquired: Cow<'a, [(u16, f64)]>,
+	},
+}
+
+/// A piece of a delta-encoded telemetry envelope small enough to fit in a single datagram.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Fragment<'a> {
+	/// The counter of the envelope this is a piece of.
+	pub counter: u64,
+
+	pub index: u16,
+	pub count: u16,
+
+	/// Whether the reassembled frame is LZ4-compressed, prefixed with its
+	/// uncompressed length as a little-endian `u32`.
+	pub compressed: bool,
+
+	pub payload: &'a [u8],
+}
+
+/// Why telemetry could not be encoded.
+#[derive(Debug)]
+pub enum EncodingError {
+	Postcard(postcard::Error),
+	Compression(lz4_flex::block::CompressError),
+
+	/// A frame has more valves or sensors, or more fragments, than can be indexed.
+	TooLarge,
+}
+
+impl fmt::Display for EncodingError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Postcard(error) => write!(f, "postcard error: {error}"),
+			Self::Compression(error) => write!(f, "compression error: {error}"),
+			Self::TooLarge => write!(f, "frame is too large to index"),
+		}
+	}
+}
+
+impl From<postcard::Error> for EncodingError {
+	fn from(error: postcard::Error) -> Self {
+		EncodingError::Postcard(error)
+	}
+}
+
+/// Encodes the vehicle state of a single telemetry stream into fragmented,
+/// delta-encoded envelopes.
+///
+/// Every buffer is kept across frames, so encoding a delta allocates nothing
+/// once the buffers have grown to fit.
+#[derive(Debug)]
+pub struct Encoder {
+	keyframe_interval: u32,
+	compress: bool,
+
+	/// The largest payload carried by a single fragment.
+	fragment_size: usize,
+
+	/// How many deltas have been sent since the last keyframe, or `None` before the first keyframe.
+	since_keyframe: Option<u32>,
+
+	valve_names: Vec<String>,
+	sensor_names: Vec<String>,
+	acquired_names: Vec<String>,
+	valve_indices: HashMap<String, u16>,
+	sensor_indices: HashMap<String, u16>,
+	acquired_indices: HashMap<String, u16>,
+
+	/// The values most recently sent, by index.
+	valves: Vec<CompositeValveState>,
+	sensors: Vec<Measurement>,
+	acquired: Vec<f64>,
+
+	changed_valves: Vec<(u16, CompositeValveState)>,
+	changed_sensors: Vec<(u16, Measurement)>,
+	changed_acquired: Vec<(u16, f64)>,
+	frame: Vec<u8>,
+	compressed: Vec<u8>,
+	datagram: Vec<u8>,
+}
+
+impl Encoder {
+	pub fn new(keyframe_interval: u32, compress: bool, fragment_size: usize) -> Self {
+		Encoder {
+			keyframe_interval,
+			compress,
+			fragment_size,
+			since_keyframe: None,
+			valve_names: Vec::new(),
+			sensor_names: Vec::new(),
+			acquired_names: Vec::new(),
+			valve_indices: HashMap::new(),
+			sensor_indices: HashMap::new(),
+			acquired_indices: HashMap::new(),
+			valves: Vec::new(),
+			sensors: Vec::new(),
+			acquired: Vec::new(),
+			changed_valves: Vec::new(),
+			changed_sensors: Vec::new(),
+			changed_acquired: Vec::new(),
+			frame: Vec::new(),
+			compressed: Vec::new(),
+			datagram: Vec::new(),
+		}
+	}
+
+	/// Encodes the next frame of the vehicle state and the acquisition times of
+	/// its readings, passing each of its datagrams to `send`.
+	///
+	/// The counter must be one more than that of the previous frame, or deltas
+	/// will be discarded by the receiver until the next keyframe.
+	///
+	/// A keyframe is sent first, every `keyframe_interval` deltas, and whenever a
+	/// valve, sensor, or acquisition time has been added or removed.
+	pub fn encode(&mut self, counter: u64, timestamp: f64, state: &VehicleState, acquired: &HashMap<String, f64>, mut send: impl FnMut(&[u8])) -> Result<(), EncodingError> {
+		// diffing fails if anything has been added or removed since the last keyframe
+		let keyframe = match self.since_keyframe {
+			Some(since) => since >= self.keyframe_interval || !self.diff(state, acquired),
+			None => true,
+		};
+
+		let frame = if keyframe {
+			self.index(state, acquired)?;
+			self.since_keyframe = Some(0);
+
+			TelemetryFrame::Keyframe {
+				valve_names: Cow::Borrowed(&self.valve_names),
+				sensor_names: Cow::Borrowed(&self.sensor_names),
+				acquired_names: Cow::Borrowed(&self.acquired_names),
+				valves: Cow::Borrowed(&self.valves),
+				sensors: Cow::Borrowed(&self.sensors),
+				acquired: Cow::Borrowed(&self.acquired),
+			}
+		} else {
+			self.since_keyframe = self.since_keyframe.map(|since| since + 1);
+
+			TelemetryFrame::Delta {
+				valves: Cow::Borrowed(&self.changed_valves),
+				sensors: Cow::Borrowed(&self.changed_sensors),
+				acquired: Cow::Borrowed(&self.changed_acquired),
+			}
+		};
+
+		let envelope = TelemetryEnvelope { counter, timestamp, frame };
+
+		self.frame.clear();
+		self.frame = postcard::to_extend(&envelope, mem::take(&mut self.frame))?;
+
+		let payload = if self.compress {
+			compress_into(&self.frame, &mut self.compressed)?;
+			&self.compressed
+		} else {
+			&self.frame
+		};
+
+		let count = payload
+			.len()
+			.div_ceil(self.fragment_size)
+			.max(1);
+
+		let count = u16::try_from(count).map_err(|_| EncodingError::TooLarge)?;
+
+		// an empty payload still needs a fragment, which chunks() would not produce
+		let chunks = payload
+			.chunks(self.fragment_size)
+			.chain(payload.is_empty().then_some(&[][..]));
+
+		for (index, chunk) in chunks.enumerate() {
+			let fragment = Fragment {
+				counter,
+				index: index as u16,
+				count,
+				compressed: self.compress,
+				payload: chunk,
+			};
+
+			self.datagram.clear();
+			self.datagram = postcard::to_extend(&fragment, mem::take(&mut self.datagram))?;
+			send(&self.datagram);
+		}
+
+		Ok(())
+	}
+
+	/// Reassigns indices to every valve, sensor, and acquisition time, taking their current values.
+	fn index(&mut self, state: &VehicleState, acquired: &HashMap<String, f64>) -> Result<(), EncodingError> {
+		index_map(&state.valve_states, &mut self.valve_names, &mut self.valve_indices, &mut self.valves)?;
+		index_map(&state.sensor_readings, &mut self.sensor_names, &mut self.sensor_indices, &mut self.sensors)?;
+		index_map(acquired, &mut self.acquired_names, &mut self.acquired_indices, &mut self.acquired)
+	}
+
+	/// Collects the values which changed since the last frame, remembering them
+	/// as sent. Returns `false` if anything is not indexed, in which case a
+	/// keyframe must be sent instead.
+	fn diff(&mut self, state: &VehicleState, acquired: &HashMap<String, f64>) -> bool {
+		diff_map(&state.valve_states, &self.valve_indices, &mut self.valves, &mut self.changed_valves)
+			&& diff_map(&state.sensor_readings, &self.sensor_indices, &mut self.sensors, &mut self.changed_sensors)
+			&& diff_map(acquired, &self.acquired_indices, &mut self.acquired, &mut self.changed_acquired)
+	}
+}
+
+/// Assigns indices to the entries of the map in order of their names.
+fn index_map<V: Clone>(map: &HashMap<String, V>, names: &mut Vec<String>, indices: &mut HashMap<String, u16>, values: &mut Vec<V>) -> Result<(), EncodingError> {
+	if map.len() > u16::MAX as usize {
+		return Err(EncodingError::TooLarge);
+	}
+
+	names.clear();
+	names.extend(map.keys().cloned());
+	names.sort_unstable();
+
+	indices.clear();
+	indices.extend(names.iter().enumerate().map(|(index, name)| (name.clone(), index as u16)));
+
+	values.clear();
+	values.extend(names.iter().map(|name| map[name].clone()));
+
+	Ok(())
+}
+
+/// Collects the entries of the map which differ from the values last sent,
+/// returning `false` if the map's names differ from the indexed names.
+fn diff_map<V: Clone + PartialEq>(map: &HashMap<String, V>, indices: &HashMap<String, u16>, values: &mut [V], changed: &mut Vec<(u16, V)>) -> bool {
+	changed.clear();
+
+	// with equal lengths, every name being indexed means no name was removed either
+	if map.len() != indices.len() {
+		return false;
+	}
+
+	for (name, value) in map {
+		let Some(index) = indices.get(name) else {
+			return false;
+		};
+
+		let sent = &mut values[*index as usize];
+
+		if sent != value {
+			sent.clone_from(value);
+			changed.push((*index, value.clone()));
+		}
+	}
+
+	true
+}
+
+/// Compresses the frame into the buffer, prefixed with its length as `lz4_flex` expects.
+fn compress_into(frame: &[u8], compressed: &mut Vec<u8>) -> Result<(), EncodingError> {
+	let length = u32::try_from(frame.len()).map_err(|_| EncodingError::TooLarge)?;
+
+	compressed.clear();
+	compressed.extend_from_slice(&length.to_le_bytes());
+	compressed.resize(4 + lz4_flex::block::get_maximum_output_size(frame.len()), 0);
+
+	let written = lz4_flex::block::compress_into(frame, &mut compressed[4..])
+		.map_err(EncodingError::Compression)?;
+
+	compressed.truncate(4 + written);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::{Unit, ValveState};
+
+	/// Reassembles fragments into envelopes and applies their frames to a copy of
+	/// the vehicle state, as the server does with a delta-encoded telemetry stream.
+	#[derive(Debug, Default)]
+	struct Decoder {
+		/// The frame being reassembled.
+		partial: Option<PartialFrame>,
+
+		/// The counter of the last frame applied, or `None` if the decoder must
+		/// wait for a keyframe.
+		counter: Option<u64>,
+
+		valve_names: Vec<String>,
+		sensor_names: Vec<String>,
+		acquired_names: Vec<String>,
+		state: VehicleState,
+		acquired: HashMap<String, f64>,
+
+		/// The timestamp of the last frame applied.
+		timestamp: f64,
+	}
+
+	/// The fragments received so far of a frame.
+	#[derive(Debug)]
+	struct PartialFrame {
+		counter: u64,
+		fragments: Vec<Option<Vec<u8>>>,
+		compressed: bool,
+	}
+
+	impl Decoder {
+		/// Handles a single datagram, returning whether it completed a frame which
+		/// was applied to the state.
+		///
+		/// Fragments of an older frame still being reassembled are discarded once a
+		/// fragment of a newer frame arrives, and deltas which do not directly follow
+		/// the last frame applied are discarded until the next keyframe.
+		fn receive(&mut self, datagram: &[u8]) -> Result<bool, EncodingError> {
+			let fragment = postcard::from_bytes::<Fragment>(datagram)?;
+
+			let partial = match self.partial.take() {
+				Some(partial) if partial.counter == fragment.counter => partial,
+				_ => PartialFrame {
+					counter: fragment.counter,
+					fragments: vec![None; fragment.count as usize],
+					compressed: fragment.compressed,
+				},
+			};
+
+			let partial = self.partial.insert(partial);
+
+			let Some(slot) = partial.fragments.get_mut(fragment.index as usize) else {
+				return Err(EncodingError::TooLarge);
+			};
+
+			*slot = Some(fragment.payload.to_vec());
+
+			if partial.fragments.iter().any(Option::is_none) {
+				return Ok(false);
+			}
+
+			let payload: Vec<u8> = partial.fragments.iter().flatten().flatten().copied().collect();
+			let compressed = partial.compressed;
+			self.partial = None;
+
+			let frame = if compressed {
+				lz4_flex::block::decompress_size_prepended(&payload).expect("compressed frames should decompress")
+			} else {
+				payload
+			};
+
+			Ok(self.apply(postcard::from_bytes(&frame)?))
+		}
+
+		/// Applies a reassembled envelope to the state, returning whether it could be.
+		fn apply(&mut self, envelope: TelemetryEnvelope) -> bool {
+			let TelemetryEnvelope { counter, timestamp, frame } = envelope;
+
+			match frame {
+				TelemetryFrame::Full { state, acquired } => {
+					self.state = state.into_owned();
+					self.acquired = acquired.into_owned();
+				},
+				TelemetryFrame::Keyframe { valve_names, sensor_names, acquired_names, valves, sensors, acquired } => {
+					self.state.valve_states = valve_names.iter().cloned().zip(valves.iter().cloned()).collect();
+					self.state.sensor_readings = sensor_names.iter().cloned().zip(sensors.iter().cloned()).collect();
+					self.acquired = acquired_names.iter().cloned().zip(acquired.iter().copied()).collect();
+					self.valve_names = valve_names.into_owned();
+					self.sensor_names = sensor_names.into_owned();
+					self.acquired_names = acquired_names.into_owned();
+				},
+				TelemetryFrame::Delta { valves, sensors, acquired } => {
+					if self.counter.map(|last| last.wrapping_add(1)) != Some(counter) {
+						self.counter = None;
+						return false;
+					}
+
+					apply_changes(&mut self.state.valve_states, &self.valve_names, &valves);
+					apply_changes(&mut self.state.sensor_readings, &self.sensor_names, &sensors);
+					apply_changes(&mut self.acquired, &self.acquired_names, &acquired);
+				},
+			}
+
+			self.counter = Some(counter);
+			self.timestamp = timestamp;
+			true
+		}
+	}
+
+	/// Updates the entries of the map named by the indices of the changes.
+	fn apply_changes<V: Clone>(map: &mut HashMap<String, V>, names: &[String], changes: &[(u16, V)]) {
+		for (index, value) in changes {
+			if let Some(name) = names.get(*index as usize) {
+				map.insert(name.clone(), value.clone());
+			}
+		}
+	}
+
+	/// A vehicle state with a few valves and enough sensors that its frames span several fragments.
+	fn vehicle_state() -> (VehicleState, HashMap<String, f64>) {
+		let mut state = VehicleState::new();
+		let mut acquired = HashMap::new();
+
+		for valve in 0..4 {
+			state.valve_states.insert(format!("valve_{valve}"), CompositeValveState {
+				commanded: ValveState::Closed,
+				actual: ValveState::Closed,
+			});
+		}
+
+		for sensor in 0..64 {
+			let name = format!("sensor_{sensor}");
+			state.sensor_readings.insert(name.clone(), Measurement { value: sensor as f64, unit: Unit::Psi });
+			acquired.insert(name, 0.0);
+		}
+
+		(state, acquired)
+	}
+
+	/// Changes one sensor, along with when it was acquired, and one valve.
+	fn change(state: &mut VehicleState, acquired: &mut HashMap<String, f64>, frame: u64) {
+		let sensor = format!("sensor_{}", frame % 64);
+		state.sensor_readings.get_mut(&sensor).unwrap().value += 1.0;
+		*acquired.get_mut(&sensor).unwrap() = frame as f64;
+
+		let valve = state.valve_states.get_mut(&format!("valve_{}", frame % 4)).unwrap();
+		valve.actual = if valve.actual == ValveState::Open { ValveState::Closed } else { ValveState::Open };
+	}
+
+	/// Encodes a frame, returning its datagrams.
+	fn encode(encoder: &mut Encoder, counter: u64, state: &VehicleState, acquired: &HashMap<String, f64>) -> Vec<Vec<u8>> {
+		let mut datagrams = Vec::new();
+		encoder.encode(counter, counter as f64, state, acquired, |datagram| datagrams.push(datagram.to_vec())).unwrap();
+		datagrams
+	}
+
+	/// Passes every datagram to the decoder, returning whether the last completed a frame.
+	fn receive(decoder: &mut Decoder, datagrams: &[Vec<u8>]) -> bool {
+		datagrams
+			.iter()
+			.map(|datagram| decoder.receive(datagram).unwrap())
+			.last()
+			.unwrap()
+	}
+
+	#[test]
+	fn deltas_follow_keyframe() {
+		for compress in [false, true] {
+			let (mut state, mut acquired) = vehicle_state();
+			let mut encoder = Encoder::new(100, compress, 256);
+			let mut decoder = Decoder::default();
+
+			let keyframe = encode(&mut encoder, 0, &state, &acquired);
+			assert!(keyframe.len() > 1, "keyframe should span several fragments");
+			assert!(receive(&mut decoder, &keyframe));
+
+			for counter in 1..10 {
+				change(&mut state, &mut acquired, counter);
+				let delta = encode(&mut encoder, counter, &state, &acquired);
+
+				assert_eq!(delta.len(), 1, "delta should fit in a single fragment");
+				assert!(receive(&mut decoder, &delta));
+				assert_eq!(decoder.state, state);
+				assert_eq!(decoder.acquired, acquired);
+				assert_eq!(decoder.timestamp, counter as f64);
+			}
+		}
+	}
+
+	#[test]
+	fn lost_fragment_drops_frame() {
+		let (state, acquired) = vehicle_state();
+		let mut encoder = Encoder::new(100, false, 256);
+		let mut decoder = Decoder::default();
+
+		let mut keyframe = encode(&mut encoder, 0, &state, &acquired);
+		keyframe.remove(1);
+
+		for datagram in &keyframe {
+			assert!(!decoder.receive(datagram).unwrap());
+		}
+
+		assert_eq!(decoder.state, VehicleState::new());
+
+		// the next frame discards the incomplete one rather than waiting for it forever
+		let next = encode(&mut Encoder::new(100, false, 256), 1, &state, &acquired);
+		assert!(receive(&mut decoder, &next));
+		assert_eq!(decoder.state, state);
+	}
+
+	#[test]
+	fn counter_gap_waits_for_keyframe() {
+		let (mut state, mut acquired) = vehicle_state();
+		let mut encoder = Encoder::new(3, true, 256);
+		let mut decoder = Decoder::default();
+
+		assert!(receive(&mut decoder, &encode(&mut encoder, 0, &state, &acquired)));
+
+		// frame 1 is lost entirely
+		change(&mut state, &mut acquired, 1);
+		encode(&mut encoder, 1, &state, &acquired);
+
+		for counter in 2..=3 {
+			change(&mut state, &mut acquired, counter);
+			assert!(!receive(&mut decoder, &encode(&mut encoder, counter, &state, &acquired)));
+		}
+
+		assert_ne!(decoder.state, state);
+
+		// three deltas have been sent since the keyframe, so the next frame is a keyframe
+		change(&mut state, &mut acquired, 4);
+		assert!(receive(&mut decoder, &encode(&mut encoder, 4, &state, &acquired)));
+		assert_eq!(decoder.state, state);
+		assert_eq!(decoder.acquired, acquired);
+	}
+
+	#[test]
+	fn added_sensor_forces_keyframe() {
+		let (mut state, mut acquired) = vehicle_state();
+		let mut encoder = Encoder::new(100, false, 256);
+		let mut decoder = Decoder::default();
+
+		assert!(receive(&mut decoder, &encode(&mut encoder, 0, &state, &acquired)));
+
+		state.sensor_readings.insert("added".to_owned(), Measurement { value: 1.0, unit: Unit::Volts });
+		acquired.insert("added".to_owned(), 1.0);
+
+		assert!(receive(&mut decoder, &encode(&mut encoder, 1, &state, &acquired)));
+		assert_eq!(decoder.state, state);
+		assert_eq!(decoder.acquired, acquired);
+	}
+}
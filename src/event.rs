@@ -0,0 +1,96 @@
+use common::comm::BoardId;
+use std::{cmp::Reverse, collections::BinaryHeap, sync::mpsc::{Receiver, RecvTimeoutError, Sender}, thread, time::{Duration, Instant}};
+use crate::{abort::AbortEvent, arming::ArmState, control::ControlEvent, state::SharedState};
+
+/// Sends events to the main loop.
+pub type EventSender = Sender<Event>;
+
+/// Anything which may drive a transition of the main loop's `ProgramState`.
+#[derive(Debug)]
+pub enum Event {
+	/// Something happened on one of the control connections.
+	Control(ControlEvent),
+
+	/// A sequence, whether started by the operator, the abort, or the loss of the
+	/// ground, has finished running, by name.
+	SequenceFinished(String),
+
+	/// A board was heard from for the first time since being declared dead,
+	/// or has not been heard from for longer than `TIME_TIL_DEATH`.
+	BoardStatus {
+		board_id: BoardId,
+		alive: bool,
+	},
+
+	/// An abort was requested, after the abort sequence has been started.
+	Aborted(AbortEvent),
+
+	/// The vehicle has moved to a new arm state.
+	ArmState(ArmState),
+
+	/// A timer scheduled with `EventLoop::schedule` has expired.
+	Timer(Timer),
+}
+
+/// Work which the main loop has scheduled for later.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Timer {
+	/// The arm token issued at the given time may have expired.
+	ArmTokenExpiry(Instant),
+}
+
+/// Multiplexes events sent from every other thread with timers scheduled by
+/// the main loop itself.
+#[derive(Debug)]
+pub struct EventLoop {
+	events: Receiver<Event>,
+
+	/// Pending timers, the soonest first.
+	timers: BinaryHeap<Reverse<(Instant, Timer)>>,
+}
+
+impl EventLoop {
+	/// Creates an event loop receiving the events sent on the other end of the channel.
+	pub fn new(events: Receiver<Event>) -> Self {
+		EventLoop { events, timers: BinaryHeap::new() }
+	}
+
+	/// Schedules a timer to expire after the given delay.
+	pub fn schedule(&mut self, delay: Duration, timer: Timer) {
+		self.timers.push(Reverse((Instant::now() + delay, timer)));
+	}
+
+	/// Blocks until the next event arrives or timer expires, whichever is first.
+	///
+	/// Returns `None` once every sender has been dropped and no timers remain.
+	pub fn next(&mut self) -> Option<Event> {
+		loop {
+			let Some(Reverse((deadline, timer))) = self.timers.peek().copied() else {
+				return self.events.recv().ok();
+			};
+
+			if deadline <= Instant::now() {
+				self.timers.pop();
+				return Some(Event::Timer(timer));
+			}
+
+			match self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+				Ok(event) => return Some(event),
+				// loop around to expire the timer
+				Err(RecvTimeoutError::Timeout) => {},
+				Err(RecvTimeoutError::Disconnected) => {
+					// nothing but the timers remains, so wait for the next one
+					thread::sleep(deadline.saturating_duration_since(Instant::now()));
+				},
+			}
+		}
+	}
+}
+
+/// Sends an event to the main loop, from any thread.
+///
+/// The main loop holds a sender in the shared state for as long as it runs,
+/// so sending can only fail while the process is exiting.
+pub fn notify(shared: &SharedState, event: Event) {
+	let _ = shared.events.lock().unwrap().send(event);
+}
@@ -1,35 +1,283 @@
-use crate::state::SharedState;
+use common::comm::VehicleState;
+use crate::{encoding::{Encoder, TelemetryEncoding, TelemetryEnvelope, TelemetryFrame}, protocol::FlightStatus, snapshot, state::SharedState, versioning::ConfigVersion, STATUS_PERIOD, STATUS_PORT, TELEMETRY_FRAGMENT_SIZE, TELEMETRY_PERIOD, TELEMETRY_POLL_PERIOD, TELEMETRY_PORT};
 use jeflog::fail;
-use std::{net::UdpSocket, thread, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashMap, net::{IpAddr, SocketAddr, UdpSocket}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
+/// A stream of vehicle state telemetry, configured by the server so that, for
+/// example, a radio link gets a slow subset while the pad network gets everything.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TelemetryStream {
+	/// Identifies the stream in logs, e.g. `"radio"`.
+	pub name: String,
+
+	/// Where the stream is sent, or `None` to send it to every connected server
+	/// on `TELEMETRY_PORT`.
+	pub destination: Option<SocketAddr>,
+
+	/// How often the stream is sent. A 5 Hz stream has a period of 200 ms.
+	pub period: Duration,
+
+	/// The names of the valves and sensors to include, or `None` to include everything.
+	pub subscription: Option<Vec<String>>,
+
+	/// How the stream is encoded. Delta encoding suits slow links and large mappings.
+	pub encoding: TelemetryEncoding,
+}
+
+impl TelemetryStream {
+	/// The stream sent until the server configures any, which is everything, at
+	/// full rate, to every connected server.
+	pub fn full_rate() -> Self {
+		TelemetryStream {
+			name: "default".to_owned(),
+			destination: None,
+			period: TELEMETRY_PERIOD,
+			subscription: None,
+			encoding: TelemetryEncoding::Full,
+		}
+	}
+}
+
+/// A configured stream along with when it was last sent.
+struct StreamState {
+	stream: TelemetryStream,
+	last_sent: Option<Instant>,
+
+	/// The counter of the next envelope sent on the stream.
+	counter: u64,
+
+	/// The subscribed subset of the vehicle state and acquisition times, kept
+	/// across sends so that they are not reallocated every time. Unused if the
+	/// stream is not filtered.
+	snapshot: VehicleState,
+	acquired: HashMap<String, f64>,
+
+	/// The delta encoder, if the stream is delta-encoded.
+	encoder: Option<Encoder>,
+
+	/// Where the stream is being sent, refreshed once per send and kept across
+	/// sends so that it is not reallocated every time.
+	destinations: Vec<SocketAddr>,
+}
+
+impl StreamState {
+	/// When the stream should next be sent.
+	fn next_due(&self) -> Option<Instant> {
+		self.last_sent.map(|sent| sent + self.stream.period)
+	}
+
+	fn is_due(&self, now: Instant) -> bool {
+		match self.next_due() {
+			Some(due) => due <= now,
+			None => true,
+		}
+	}
+}
+
+/// Constructs a closure which forwards every telemetry stream, and periodically
+/// the flight computer's status, to their destinations.
+///
+/// Each frame is sent in a `TelemetryEnvelope` with a counter, the time the
+/// snapshot was taken, and when each reading was acquired by its board, both in
+/// the flight computer's clock.
+///
+/// The vehicle state lock is only held while copying the state into a snapshot,
+/// which is then serialized into a reused buffer and sent with no lock held, so
+/// a slow network cannot stall the worker updating the state.
 pub fn forward_vehicle_state(shared: &SharedState) -> impl Fn() -> () {
-	let server_address = shared.server_address.clone();
-	let vehicle_state = shared.vehicle_state.clone();
+	let shared = shared.clone();
 
 	let socket = UdpSocket::bind("0.0.0.0:0")
 		.expect("failed to bind to UDP socket");
 
 	move || {
+		let mut last_status: Option<Instant> = None;
+		let mut streams = Vec::new();
+		let mut streams_version: Option<ConfigVersion> = None;
+		let mut server_addresses = Vec::new();
+		let mut snapshot = VehicleState::new();
+		let mut acquired = HashMap::new();
+		let mut serialized = Vec::new();
+
 		loop {
-			if let Some(server_address) = *server_address.lock().unwrap() {
-				let vehicle_state = vehicle_state.lock().unwrap();
+			// the streams are only copied when the server changes them
+			let version = shared.config_versions.lock().unwrap().telemetry_streams;
+
+			if streams_version != Some(version) {
+				streams = load_streams(&shared, &streams);
+				streams_version = Some(version);
+			}
+
+			// telemetry is forwarded to every connected server, whether or not it holds authority
+			server_addresses.clear();
+			server_addresses.extend(shared.server_addresses.lock().unwrap().values().copied());
+
+			let now = Instant::now();
+
+			// streams sent to the servers are skipped while none are connected
+			let due = |state: &StreamState| {
+				state.is_due(now) && (state.stream.destination.is_some() || !server_addresses.is_empty())
+			};
 
-				// TODO: Change to something that doesn't allocate every iteration
-				match postcard::to_allocvec(&*vehicle_state) {
-					Ok(serialized) => {
-						let result = socket.send_to(&serialized, (server_address, 7201));
+			if streams.iter().any(due) {
+				// both are taken under the vehicle state lock so that they agree
+				let vehicle_state = shared.vehicle_state.lock().unwrap();
+				snapshot::copy_state(&mut snapshot, &vehicle_state);
+				snapshot::copy_map(&mut acquired, &shared.acquisition_times.lock().unwrap());
+				drop(vehicle_state);
+
+				let timestamp = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.map(|time| time.as_secs_f64())
+					.unwrap_or(0.0);
+
+				for state in streams.iter_mut().filter(|state| due(state)) {
+					state.last_sent = Some(now);
+					forward_stream(state, &snapshot, &acquired, timestamp, &socket, &server_addresses, &mut serialized);
+					state.counter = state.counter.wrapping_add(1);
+				}
+			}
 
-						if result.is_err() {
-							fail!("Failed to send vehicle state update to server at \x1b[1m{server_address}:7201\x1b[0m.");
-						}
+			if !server_addresses.is_empty() {
+				match last_status {
+					Some(sent) if sent.elapsed() < STATUS_PERIOD => {},
+					_ => {
+						forward_status(&shared, &socket, &server_addresses, &mut serialized);
+						last_status = Some(Instant::now());
 					},
-					Err(error) => {
-						fail!("Failed to serialize vehicle state with Postcard: {}.", error.to_string());
-					}
 				}
 			}
 
-			thread::sleep(Duration::from_millis(10));
+			// polls at least this often so that changes to the streams and servers are noticed
+			let wait = streams
+				.iter()
+				.filter_map(StreamState::next_due)
+				.min()
+				.map_or(TELEMETRY_POLL_PERIOD, |due| due.saturating_duration_since(Instant::now()))
+				.min(TELEMETRY_POLL_PERIOD);
+
+			thread::sleep(wait);
+		}
+	}
+}
+
+/// Copies the configured streams, or the full rate stream if none are configured.
+///
+/// Streams keep their counters across reloads by name, so that the server does
+/// not mistake a reload for lost or reordered frames.
+fn load_streams(shared: &SharedState, previous: &[StreamState]) -> Vec<StreamState> {
+	let mut streams = shared.telemetry_streams
+		.lock()
+		.unwrap()
+		.clone();
+
+	if streams.is_empty() {
+		streams.push(TelemetryStream::full_rate());
+	}
+
+	streams
+		.into_iter()
+		.map(|stream| {
+			let encoder = match stream.encoding {
+				TelemetryEncoding::Full => None,
+				TelemetryEncoding::Delta { keyframe_interval, compress } => Some(Encoder::new(keyframe_interval, compress, TELEMETRY_FRAGMENT_SIZE)),
+			};
+
+			let counter = previous
+				.iter()
+				.find(|state| state.stream.name == stream.name)
+				.map_or(0, |state| state.counter);
+
+			StreamState {
+				stream,
+				last_sent: None,
+				counter,
+				snapshot: VehicleState::new(),
+				acquired: HashMap::new(),
+				encoder,
+				destinations: Vec::new(),
+			}
+		})
+		.collect()
+}
+
+/// Sends the subscribed part of the vehicle state snapshot and its acquisition
+/// times to the stream's destination.
+fn forward_stream(state: &mut StreamState, snapshot: &VehicleState, acquired: &HashMap<String, f64>, timestamp: f64, socket: &UdpSocket, server_addresses: &[IpAddr], serialized: &mut Vec<u8>) {
+	let (subset, acquired) = match &state.stream.subscription {
+		Some(subscription) => {
+			snapshot::copy_subscribed(&mut state.snapshot, snapshot, subscription);
+			snapshot::copy_subscribed_map(&mut state.acquired, acquired, subscription);
+			(&state.snapshot, &state.acquired)
+		},
+		None => (snapshot, acquired),
+	};
+
+	let counter = state.counter;
+
+	let name = &state.stream.name;
+
+	state.destinations.clear();
+
+	match state.stream.destination {
+		Some(destination) => state.destinations.push(destination),
+		None => state.destinations.extend(server_addresses.iter().map(|address| SocketAddr::new(*address, TELEMETRY_PORT))),
+	}
+
+	let destinations = &state.destinations;
+
+	let send = |datagram: &[u8]| {
+		for destination in destinations {
+			if socket.send_to(datagram, destination).is_err() {
+				fail!("Failed to send telemetry stream '{name}' to \x1b[1m{destination}\x1b[0m.");
+			}
+		}
+	};
+
+	match &mut state.encoder {
+		Some(encoder) => {
+			if let Err(error) = encoder.encode(counter, timestamp, subset, acquired, send) {
+				fail!("Failed to encode telemetry stream '{name}': {error}.");
+			}
+		},
+		None => {
+			let envelope = TelemetryEnvelope {
+				counter,
+				timestamp,
+				frame: TelemetryFrame::Full {
+					state: Cow::Borrowed(subset),
+					acquired: Cow::Borrowed(acquired),
+				},
+			};
+
+			match snapshot::serialize_into(&envelope, serialized) {
+				Ok(()) => send(serialized),
+				Err(error) => fail!("Failed to serialize vehicle state with Postcard: {}.", error.to_string()),
+			}
+		},
+	}
+}
+
+/// Sends the flight computer's status to every connected server.
+fn forward_status(shared: &SharedState, socket: &UdpSocket, server_addresses: &[IpAddr], serialized: &mut Vec<u8>) {
+	let status = FlightStatus {
+		configuration: *shared.config_versions.lock().unwrap(),
+		command_queues: shared.command_queues.lock().unwrap().clone(),
+		arm_state: shared.arming.lock().unwrap().state,
+		authority: *shared.authority.lock().unwrap(),
+	};
+
+	match snapshot::serialize_into(&status, serialized) {
+		Ok(()) => {
+			for server_address in server_addresses {
+				if socket.send_to(serialized, (*server_address, STATUS_PORT)).is_err() {
+					fail!("Failed to send status update to server at \x1b[1m{server_address}:{STATUS_PORT}\x1b[0m.");
+				}
+			}
+		},
+		Err(error) => {
+			fail!("Failed to serialize flight status with Postcard: {}.", error.to_string());
 		}
 	}
 }
@@ -1,18 +1,21 @@
-use common::{comm::{BoardId, CompositeValveState, NodeMapping, SamControlMessage, ValveState, VehicleState}, sequence::{self, AbortError, DeviceAction}};
-use jeflog::{fail, warn};
-use pyo3::{types::PyNone, IntoPy, PyErr, PyObject, Python, ToPyObject};
-use std::{sync::{mpsc::Sender, Mutex}, thread};
+use common::{comm::{CompositeValveState, SamControlMessage, ValveState, VehicleState}, sequence::{self, AbortError, DeviceAction}};
+use jeflog::{fail, pass, warn};
+use pyo3::{create_exception, exceptions::PyException, types::PyNone, IntoPy, PyErr, PyObject, Python, ToPyObject};
+use std::{sync::{mpsc::{self, Receiver}, Mutex}, thread, time::Instant};
 
-use crate::state::SharedState;
+use crate::{abort::{AbortCause, AbortEvent}, arming::{self, ArmState}, control, event::{self, Event}, interlock::{self, InterlockViolation}, peer, protocol::{self, FlightReport}, state::SharedState, valve::MismatchMonitor, CommandSender, ABORT_SEQUENCE_NAME, TRIGGER_SEQUENCE_PREFIX};
 
-pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, SamControlMessage)>) -> impl Fn(&str, DeviceAction) -> PyObject {
+create_exception!(flight, InterlockError, PyException, "Raised when a valve command is rejected because it would violate an interlock.");
+create_exception!(flight, ArmingError, PyException, "Raised when a valve command is rejected because the vehicle is not armed.");
+
+pub fn create_device_handler(shared: SharedState, command_tx: CommandSender) -> impl Fn(&str, DeviceAction) -> PyObject {
 	let tx = command_tx.clone();
 
 	move |device, action| {
 		let thread_id = thread::current().id();
 		let sequences = shared.sequences.lock().unwrap();
-		
-		if sequences.get_by_right(&thread_id).is_none() {
+
+		let Some(sequence_name) = sequences.get_by_right(&thread_id).cloned() else {
 			drop(sequences);
 
 			return Python::with_gil(|py| {
@@ -22,7 +25,7 @@ pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, S
 
 				PyNone::get(py).to_object(py)
 			});
-		}
+		};
 
 		drop(sequences);
 
@@ -30,11 +33,18 @@ pub fn create_device_handler(shared: SharedState, command_tx: Sender<(BoardId, S
 			DeviceAction::ReadSensor => read_sensor(device, &shared.vehicle_state),
 			DeviceAction::ReadValveState => read_valve_state(device, &shared.vehicle_state),
 			DeviceAction::ActuateValve { state } => {
-				actuate_valve(device, state, &shared.mappings, &shared.vehicle_state, &tx);
+				// the device handler cannot raise, so a rejection is only logged here. sequences
+				// which need to handle one use the `actuate_valve` builtin instead
+				let _ = actuate_for_sequence(&shared, &sequence_name, device, state, &tx);
 				Python::with_gil(|py| PyNone::get(py).to_object(py))
 			},
 			DeviceAction::Abort => {
-				abort(&shared);
+				let cause = match sequence_name.strip_prefix(TRIGGER_SEQUENCE_PREFIX) {
+					Some(trigger) => AbortCause::Trigger(trigger.to_owned()),
+					None => AbortCause::Sequence(sequence_name),
+				};
+
+				abort(&shared, cause);
 				Python::with_gil(|py| PyNone::get(py).to_object(py))
 			},
 		}
@@ -76,26 +86,90 @@ fn read_valve_state(name: &str, vehicle_state: &Mutex<VehicleState>) -> PyObject
 	})
 }
 
-fn actuate_valve(name: &str, state: ValveState, mappings: &Mutex<Vec<NodeMapping>>, vehicle_state: &Mutex<VehicleState>, command_tx: &Sender<(BoardId, SamControlMessage)>) {
-	let mappings = mappings.lock().unwrap();
+/// Why a valve command from a sequence was rejected.
+#[derive(Debug)]
+pub enum ActuationError {
+	/// The vehicle is not in a state which permits the sequence to actuate valves.
+	Arming(ArmState),
+
+	Interlock(InterlockViolation),
+}
+
+impl From<ActuationError> for PyErr {
+	fn from(error: ActuationError) -> Self {
+		match error {
+			ActuationError::Arming(state) => ArmingError::new_err(format!("cannot actuate valves while the vehicle is {state}")),
+			ActuationError::Interlock(violation) => InterlockError::new_err(violation.to_string()),
+		}
+	}
+}
+
+/// Commands a valve to a state on behalf of the named sequence, unless the arm
+/// state does not permit the sequence to or the command would violate an interlock.
+///
+/// Interlocks never stand in the way of the abort or safing sequence making the vehicle safe.
+pub fn actuate_for_sequence(shared: &SharedState, sequence_name: &str, name: &str, state: ValveState, command_tx: &CommandSender) -> Result<Receiver<Instant>, ActuationError> {
+	let arm_state = shared.arming.lock().unwrap().state;
+
+	let abort_sequence = sequence_name == ABORT_SEQUENCE_NAME;
+	let safing_sequence = control::is_safing_sequence(shared, sequence_name);
+
+	if !arm_state.permits_actuation(abort_sequence, safing_sequence) {
+		fail!("Refusing to actuate valve '{name}' from sequence '{sequence_name}' because the vehicle is {arm_state}.");
+		return Err(ActuationError::Arming(arm_state));
+	}
+
+	actuate_valve(shared, name, state, command_tx, abort_sequence || safing_sequence).map_err(ActuationError::Interlock)
+}
+
+/// Commands a valve to a state, unless doing so would violate an interlock, in
+/// which case the violation is logged, reported to the operator, and returned.
+///
+/// The returned receiver gets the moment the commander actually sends the command
+/// to its board, and disconnects without it if the command is never sent.
+///
+/// Interlocks are not checked for commands from the abort or safing sequence,
+/// marked by `safing`, which must be able to run to completion whatever the
+/// state of the vehicle.
+pub fn actuate_valve(shared: &SharedState, name: &str, state: ValveState, command_tx: &CommandSender, safing: bool) -> Result<Receiver<Instant>, InterlockViolation> {
+	// the vehicle state is held until the commanded state is updated so that no
+	// other command can change what the interlocks are checked against meanwhile
+	let mut vehicle_state = shared.vehicle_state.lock().unwrap();
+	let mappings = shared.mappings.lock().unwrap();
+
+	let (sent_tx, sent_rx) = mpsc::channel();
 
 	let Some(mapping) = mappings.iter().find(|m| m.text_id == name) else {
 		fail!("Failed to actuate valve: mapping '{name}' is not defined.");
-		return;
+		return Ok(sent_rx);
 	};
 
+	let interlocks = shared.interlocks.lock().unwrap();
+	let checked = if safing { Ok(()) } else { interlock::check(&interlocks, name, state, &vehicle_state) };
+
+	if let Err(violation) = checked {
+		drop(interlocks);
+		drop(mappings);
+		drop(vehicle_state);
+
+		fail!("Rejected valve command: {violation}.");
+		protocol::send_report(&shared.control_sockets, &FlightReport::InterlockViolation(violation.clone()));
+		return Err(violation);
+	}
+
+	drop(interlocks);
+
 	let closed = state == ValveState::Closed;
 	let normally_closed = mapping.normally_closed.unwrap_or(true);
 	let powered = closed != normally_closed;
 
 	let message = SamControlMessage::ActuateValve { channel: mapping.channel, powered };
 
-	if let Err(error) = command_tx.send((mapping.board_id.clone(), message)) {
+	if let Err(error) = command_tx.send((mapping.board_id.clone(), message, sent_tx)) {
 		fail!("Failed to send command: {error}");
 	}
 
 	drop(mappings);
-	let mut vehicle_state = vehicle_state.lock().unwrap();
 
 	if let Some(existing) = vehicle_state.valve_states.get_mut(name) {
 		existing.commanded = state;
@@ -105,25 +179,102 @@ fn actuate_valve(name: &str, state: ValveState, mappings: &Mutex<Vec<NodeMapping
 			actual: ValveState::Undetermined
 		});
 	}
+
+	Ok(sent_rx)
 }
 
-pub fn abort(shared: &SharedState) {
+/// Requests an abort, recording the cause.
+///
+/// Only the first request after the latch is reset actually stops running
+/// sequences and starts the abort sequence. Subsequent requests are recorded
+/// but otherwise ignored until the operator resets the latch.
+pub fn abort(shared: &SharedState, cause: AbortCause) {
+	let vehicle_state = shared.vehicle_state
+		.lock()
+		.unwrap()
+		.clone();
+
+	let event = AbortEvent::new(cause, vehicle_state);
+	let mut latch = shared.abort.lock().unwrap();
+	let first = !latch.aborted;
+
+	if first {
+		fail!("Aborting due to {} (requested by thread '{}').", event.cause, event.thread);
+		latch.aborted = true;
+	} else {
+		warn!("Abort already in progress. Recorded additional cause from thread '{}': {}.", event.thread, event.cause);
+	}
+
+	latch.record(event.clone());
+	drop(latch);
+
+	if first {
+		// a retry would resend whatever was commanded before the abort
+		shared.valve_trackers
+			.lock()
+			.unwrap()
+			.monitors
+			.values_mut()
+			.for_each(MismatchMonitor::cancel_retries);
+
+		// set before the abort sequence starts so that it is permitted to actuate valves
+		arming::transition(shared, ArmState::Aborted);
+		start_abort_sequence(shared);
+		peer::coordinate_abort(shared, &event.cause);
+	}
+
+	// reported by the main loop so that a slow control connection cannot delay the abort
+	event::notify(shared, Event::Aborted(event));
+}
+
+/// Stops every running sequence and starts the abort sequence, if one is set.
+fn start_abort_sequence(shared: &SharedState) {
 	let abort_sequence = shared.abort_sequence
 		.lock()
 		.unwrap()
 		.clone();
 
+	// stop every running sequence, whether or not there is an abort sequence to replace them
+	let mut sequences = shared.sequences.lock().unwrap();
+	sequences.clear();
+
 	let Some(sequence) = abort_sequence else {
 		warn!("Abort was called but no abort sequence is set.");
 		return;
 	};
 
-	let mut sequences = shared.sequences.lock().unwrap();
-	sequences.clear();
-	sequences.insert("abort".to_owned(), thread::current().id());
-	drop(sequences);
+	// the abort sequence gets its own thread so that the requesting thread
+	// (possibly the commander the abort sequence needs) is not blocked by it
+	let thread_shared = shared.clone();
 
-	sequence::run(sequence);
+	let thread_id = thread::spawn(move || {
+		sequence::run(sequence);
+
+		thread_shared.sequences
+			.lock()
+			.unwrap()
+			.remove_by_right(&thread::current().id());
+
+		event::notify(&thread_shared, Event::SequenceFinished(ABORT_SEQUENCE_NAME.to_owned()));
+	})
+		.thread()
+		.id();
+
+	sequences.insert(ABORT_SEQUENCE_NAME.to_owned(), thread_id);
 }
 
+/// Clears the abort latch so that sequences may run and abort again.
+pub fn reset_abort(shared: &SharedState) {
+	let mut latch = shared.abort.lock().unwrap();
+
+	if !latch.aborted {
+		warn!("Abort reset was requested, but the flight computer has not aborted.");
+		return;
+	}
+
+	latch.aborted = false;
+	drop(latch);
 
+	pass!("Abort latch reset.");
+	arming::transition(shared, ArmState::Safe);
+}
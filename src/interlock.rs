@@ -0,0 +1,194 @@
+use common::comm::{ValveState, VehicleState};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A condition which, while it holds, forbids a valve from being commanded to a state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum InterlockCondition {
+	/// The named valve has been commanded open.
+	ValveOpen(String),
+
+	/// The named valve has been commanded closed.
+	ValveClosed(String),
+
+	/// The named sensor reads above the limit, or has no reading at all.
+	SensorAbove {
+		sensor: String,
+		limit: f64,
+	},
+
+	/// The named sensor reads below the limit, or has no reading at all.
+	SensorBelow {
+		sensor: String,
+		limit: f64,
+	},
+}
+
+/// A rule forbidding a valve from being commanded to a state while a condition holds.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Interlock {
+	/// Identifies the rule in logs and reports.
+	pub name: String,
+
+	/// The `text_id` of the valve the rule applies to.
+	pub valve: String,
+
+	/// The state the valve may not be commanded to.
+	pub state: ValveState,
+
+	pub condition: InterlockCondition,
+}
+
+/// A valve command rejected because it would violate an interlock.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InterlockViolation {
+	/// The name of the violated interlock.
+	pub interlock: String,
+
+	pub valve: String,
+	pub state: ValveState,
+
+	/// Why the interlock's condition holds.
+	pub reason: String,
+}
+
+impl fmt::Display for InterlockViolation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "commanding '{}' {} violates interlock '{}': {}", self.valve, self.state, self.interlock, self.reason)
+	}
+}
+
+impl InterlockCondition {
+	/// Returns why the condition holds, or `None` if it does not.
+	fn holds(&self, vehicle_state: &VehicleState) -> Option<String> {
+		match self {
+			Self::ValveOpen(valve) => valve_in_state(vehicle_state, valve, ValveState::Open),
+			Self::ValveClosed(valve) => valve_in_state(vehicle_state, valve, ValveState::Closed),
+			Self::SensorAbove { sensor, limit } => {
+				sensor_outside(vehicle_state, sensor, |value| value > *limit, &format!("above the limit of {limit}"))
+			},
+			Self::SensorBelow { sensor, limit } => {
+				sensor_outside(vehicle_state, sensor, |value| value < *limit, &format!("below the limit of {limit}"))
+			},
+		}
+	}
+}
+
+/// A valve is considered to be in a state once it has been commanded to it, so
+/// that commands sent moments apart are still caught. Its actual state is not
+/// considered, since it lags behind every command and would otherwise block the
+/// command following one which closes or opens it.
+fn valve_in_state(vehicle_state: &VehicleState, valve: &str, state: ValveState) -> Option<String> {
+	let composite = vehicle_state.valve_states.get(valve)?;
+
+	(composite.commanded == state)
+		.then(|| format!("'{valve}' is commanded {state}"))
+}
+
+/// A sensor without a reading is considered to be outside its limit, since
+/// nothing can be said about whether it is safe.
+fn sensor_outside(vehicle_state: &VehicleState, sensor: &str, outside: impl Fn(f64) -> bool, description: &str) -> Option<String> {
+	let Some(reading) = vehicle_state.sensor_readings.get(sensor) else {
+		return Some(format!("'{sensor}' has no reading"));
+	};
+
+	outside(reading.value).then(|| format!("'{sensor}' reads {}, {description}", reading.value))
+}
+
+/// Checks a valve command against every interlock, returning the first violation.
+pub fn check(interlocks: &[Interlock], valve: &str, state: ValveState, vehicle_state: &VehicleState) -> Result<(), InterlockViolation> {
+	for interlock in interlocks {
+		if interlock.valve != valve || interlock.state != state {
+			continue;
+		}
+
+		if let Some(reason) = interlock.condition.holds(vehicle_state) {
+			return Err(InterlockViolation {
+				interlock: interlock.name.clone(),
+				valve: valve.to_owned(),
+				state,
+				reason,
+			});
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::{CompositeValveState, Measurement, Unit};
+
+	fn interlock(name: &str, valve: &str, state: ValveState, condition: InterlockCondition) -> Interlock {
+		Interlock { name: name.to_owned(), valve: valve.to_owned(), state, condition }
+	}
+
+	fn vehicle_state(tank: Option<f64>, vent: ValveState) -> VehicleState {
+		let mut state = VehicleState::new();
+
+		if let Some(value) = tank {
+			state.sensor_readings.insert("tank".to_owned(), Measurement { value, unit: Unit::Psi });
+		}
+
+		state.valve_states.insert("vent".to_owned(), CompositeValveState { commanded: vent, actual: ValveState::Undetermined });
+		state
+	}
+
+	fn interlocks() -> Vec<Interlock> {
+		vec![
+			interlock("overpressure", "fuel", ValveState::Open, InterlockCondition::SensorAbove { sensor: "tank".to_owned(), limit: 500.0 }),
+			interlock("underpressure", "fuel", ValveState::Open, InterlockCondition::SensorBelow { sensor: "tank".to_owned(), limit: 50.0 }),
+			interlock("venting", "press", ValveState::Open, InterlockCondition::ValveOpen("vent".to_owned())),
+			interlock("sealed", "press", ValveState::Closed, InterlockCondition::ValveClosed("vent".to_owned())),
+		]
+	}
+
+	#[test]
+	fn sensor_conditions_reject_outside_limits() {
+		let interlocks = interlocks();
+
+		assert!(check(&interlocks, "fuel", ValveState::Open, &vehicle_state(Some(100.0), ValveState::Closed)).is_ok());
+
+		let violation = check(&interlocks, "fuel", ValveState::Open, &vehicle_state(Some(600.0), ValveState::Closed)).unwrap_err();
+		assert_eq!(violation.interlock, "overpressure");
+		assert_eq!(violation.reason, "'tank' reads 600, above the limit of 500");
+
+		let violation = check(&interlocks, "fuel", ValveState::Open, &vehicle_state(Some(10.0), ValveState::Closed)).unwrap_err();
+		assert_eq!(violation.interlock, "underpressure");
+	}
+
+	#[test]
+	fn missing_reading_is_a_violation() {
+		let violation = check(&interlocks(), "fuel", ValveState::Open, &vehicle_state(None, ValveState::Closed)).unwrap_err();
+
+		assert_eq!(violation.interlock, "overpressure");
+		assert_eq!(violation.reason, "'tank' has no reading");
+	}
+
+	#[test]
+	fn valve_conditions_use_commanded_state() {
+		let interlocks = interlocks();
+
+		let violation = check(&interlocks, "press", ValveState::Open, &vehicle_state(Some(100.0), ValveState::Open)).unwrap_err();
+		assert_eq!(violation.interlock, "venting");
+		assert!(check(&interlocks, "press", ValveState::Closed, &vehicle_state(Some(100.0), ValveState::Open)).is_ok());
+
+		let violation = check(&interlocks, "press", ValveState::Closed, &vehicle_state(Some(100.0), ValveState::Closed)).unwrap_err();
+		assert_eq!(violation.interlock, "sealed");
+
+		// a valve never commanded is in no state at all
+		let mut uncommanded = vehicle_state(Some(100.0), ValveState::Open);
+		uncommanded.valve_states.clear();
+		assert!(check(&interlocks, "press", ValveState::Open, &uncommanded).is_ok());
+	}
+
+	#[test]
+	fn only_matching_valve_and_state_are_checked() {
+		let unsafe_state = vehicle_state(None, ValveState::Open);
+
+		assert!(check(&interlocks(), "fuel", ValveState::Closed, &unsafe_state).is_ok());
+		assert!(check(&interlocks(), "ox", ValveState::Open, &unsafe_state).is_ok());
+		assert!(check(&[], "fuel", ValveState::Open, &unsafe_state).is_ok());
+	}
+}
@@ -1,19 +1,58 @@
+mod abort;
+mod arming;
+mod auth;
+mod builtins;
+mod control;
+mod discovery;
+mod encoding;
+mod event;
 mod forwarder;
 mod handler;
+mod interlock;
+mod persistence;
+mod peer;
+mod protocol;
+mod role;
+mod scheduler;
+mod settings;
+mod snapshot;
 mod state;
 mod switchboard;
+mod tare;
+mod validation;
+mod valve;
+mod versioning;
 
-use std::{sync::mpsc::{Receiver, Sender}, time::Duration};
+use std::{sync::mpsc::{Receiver, Sender}, time::{Duration, Instant}};
 
 use common::comm::{BoardId, SamControlMessage};
 use jeflog::pass;
 use state::ProgramState;
 
 const SERVO_PORT: u16 = 5025;
+/// File containing settings local to this flight computer, such as how to discover the control server
+const SETTINGS_PATH: &str = "/etc/flight/flight.toml";
+/// How often discovery checks whether a beacon has been heard while waiting for one
+const BEACON_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// File containing the key shared with the control server, used to authenticate the control connection
+const CONTROL_KEY_PATH: &str = "/etc/flight/control.key";
+/// How long the control server has to respond during authentication before the connection is dropped
+const CONTROL_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a frame may take to send to the control server before the connection is dropped,
+/// which bounds how long a slow server can hold up everything else sending reports
+const CONTROL_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long a control server may go without sending anything before its connection is considered lost, unless configured otherwise
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest frame accepted from the control server
+const CONTROL_MAX_FRAME_SIZE: usize = 1_000_000;
 /// Where data should be sent 
 const SWITCHBOARD_ADDRESS: (&str, u16) = ("0.0.0.0", 4573);
 /// SAM port to send DataMessage::Identity and DataMessage:Heartbeat to
 const SAM_PORT: u16 = 8378;
+/// Directory containing a `<board id>.key` file for every board, used to authenticate datagrams exchanged with it
+const BOARD_KEY_DIRECTORY: &str = "/etc/flight/boards";
+/// Longest the counters of datagrams accepted from boards go without being persisted, which bounds which captured datagrams could be replayed after a restart
+const BOARD_COUNTER_PERSIST_PERIOD: Duration = Duration::from_secs(1);
 
 /// How often heartbeats are sent
 const HEARTBEAT_PERIOD: Duration = Duration::from_millis(150);
@@ -22,6 +61,14 @@ const TIME_TIL_DEATH: Duration = Duration::from_millis(100);
 
 /// How large the buffer to send a command to a board should be (Can probably replace this with a sizeof(SamControlMessage)).
 const COMMAND_MESSAGE_BUFFER_SIZE: usize = 1_024;
+/// How long a valve has to confirm a command, via its current readback, before the command is retransmitted
+const COMMAND_CONFIRMATION_TIMEOUT: Duration = Duration::from_millis(250);
+/// How many times a valve command is sent before giving up on it being confirmed
+const COMMAND_MAX_ATTEMPTS: u32 = 3;
+/// How many commands may be waiting to be sent to a single board before the oldest is dropped
+const COMMAND_QUEUE_CAPACITY: usize = 64;
+/// How long a command may wait to be sent, e.g. for its board to identify itself, before it is dropped
+const COMMAND_QUEUE_EXPIRY: Duration = Duration::from_secs(1);
 /// How large the buffer to recieve data from a board should be (Can probably replace this with a sizeof(DataMessage)).
 const DATA_MESSAGE_BUFFER_SIZE: usize = 1_000_000;
 /// How large the buffer to send a heartbeat to a board should be (Can probably replace this with a sizeof(SamControlMessage::Heartbeat)).
@@ -30,11 +77,58 @@ const HEARTBEAT_BUFFER_SIZE: usize = 1_024;
 /// How many boards should be refreshed before checking for timeout
 const REFRESH_COUNT: u8 = 5;
 
+/// How many abort events are retained in memory for later query
+const ABORT_HISTORY_LENGTH: usize = 64;
+
+/// UDP port on the server to which vehicle state telemetry is sent
+const TELEMETRY_PORT: u16 = 7201;
+/// How often vehicle state telemetry is sent until the server configures telemetry streams
+const TELEMETRY_PERIOD: Duration = Duration::from_millis(10);
+/// Shortest period the server may configure for a telemetry stream
+const TELEMETRY_MIN_PERIOD: Duration = Duration::from_millis(1);
+/// Largest payload carried by a single datagram of a delta-encoded telemetry stream, leaving room for headers within a 1500 byte MTU
+const TELEMETRY_FRAGMENT_SIZE: usize = 1_200;
+/// Longest the forwarder waits before noticing changes to the telemetry streams or connected servers
+const TELEMETRY_POLL_PERIOD: Duration = Duration::from_millis(10);
+/// UDP port on the server to which flight computer status is sent
+const STATUS_PORT: u16 = 7202;
+/// How often flight computer status is sent
+const STATUS_PERIOD: Duration = Duration::from_secs(1);
+
+/// UDP port on which each computer listens for messages from the other, unless configured otherwise
+const PEER_PORT: u16 = 7203;
+/// File containing the key shared by the flight and ground computers, used to authenticate messages between them
+const PEER_KEY_PATH: &str = "/etc/flight/peer.key";
+/// How long to wait for the other computer to acknowledge an abort before resending it
+const PEER_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+/// How many times an abort is sent to the other computer before giving up on it being acknowledged
+const PEER_ABORT_ATTEMPTS: u32 = 20;
+
+/// How long the operator has to confirm an arm token before it expires
+const ARM_TOKEN_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Directory in which configuration received from the server is persisted across restarts
+const PERSISTENCE_DIRECTORY: &str = "/var/lib/flight";
+/// How long to wait before retrying initialization after it fails
+const INIT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How long before a scheduled valve actuation the scheduler stops sleeping and busy-waits instead
+const SCHEDULE_SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+/// Name under which the abort sequence is registered while it runs
+const ABORT_SEQUENCE_NAME: &str = "abort";
+
+/// Prefix of the names given to sequences run by triggers
+const TRIGGER_SEQUENCE_PREFIX: &str = "trigger_";
+
 
 /// Board ID of the flight computer
 const FC_BOARD_ID: &str = "flight-01";
+/// Board ID of the ground computer
+const GC_BOARD_ID: &str = "ground-01";
 
-type CommandSender = Sender<(BoardId, SamControlMessage)>;
+/// Sends a control message to a board, along with where to report when it is actually sent.
+type CommandSender = Sender<(BoardId, SamControlMessage, Sender<Instant>)>;
 
 type TuiReceiver = Receiver<TuiMessage>;
 type TuiSender = Sender<TuiMessage>;
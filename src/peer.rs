@@ -0,0 +1,288 @@
+use common::comm::Computer;
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, fs, io, net::{SocketAddr, UdpSocket}, thread, time::{SystemTime, UNIX_EPOCH}};
+use crate::{abort::AbortCause, auth, handler, persistence, role, state::SharedState, PEER_ABORT_ATTEMPTS, PEER_KEY_PATH, PEER_PORT, PEER_RETRY_INTERVAL};
+
+/// How to reach the other computer, flight or ground.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PeerSettings {
+	/// Address at which the other computer listens for peer messages.
+	pub address: SocketAddr,
+
+	/// Port on which this computer listens for peer messages.
+	#[serde(default = "default_port")]
+	pub port: u16,
+}
+
+fn default_port() -> u16 {
+	PEER_PORT
+}
+
+/// Messages exchanged between the flight and ground computers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum PeerMessage {
+	/// The sender has aborted. Retransmitted until acknowledged, so the same
+	/// abort may be received more than once.
+	Abort {
+		/// Identifies the abort across retransmissions.
+		id: u64,
+		cause: AbortCause,
+	},
+
+	/// The abort with the given ID was received.
+	AbortAcknowledged(u64),
+}
+
+/// Why a datagram from the other computer was rejected.
+#[derive(Debug)]
+enum Rejection {
+	/// The datagram is too short to hold a counter and a MAC.
+	Malformed,
+
+	/// The MAC does not match, so the datagram was not sent by the other computer.
+	Forged,
+
+	/// The counter is not greater than that of the last datagram accepted.
+	Replayed,
+
+	/// The datagram is authentic but does not hold a `PeerMessage`.
+	Undecodable(postcard::Error),
+}
+
+impl fmt::Display for Rejection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Malformed => write!(f, "datagram is too short to be authenticated"),
+			Self::Forged => write!(f, "MAC does not match"),
+			Self::Replayed => write!(f, "counter has already been used"),
+			Self::Undecodable(error) => write!(f, "failed to deserialize message: {error}"),
+		}
+	}
+}
+
+/// The authenticated link to the other computer, used to coordinate aborts.
+///
+/// Datagrams are sealed by `auth::seal_datagram`, like those exchanged with
+/// boards, under a key shared by the two computers and read from `PEER_KEY_PATH`.
+#[derive(Debug)]
+pub struct Peer {
+	socket: UdpSocket,
+	address: SocketAddr,
+	key: Vec<u8>,
+
+	/// Which computer this is, mixed into the MAC of every datagram sent so that
+	/// a datagram cannot be reflected back to its sender.
+	computer: Computer,
+
+	boot_count: u32,
+	sent: u64,
+
+	/// The counter of the last datagram accepted, persisted so that datagrams
+	/// captured before a restart cannot be replayed after it.
+	received: Option<u64>,
+
+	/// IDs of aborts sent to the other computer which it has not yet acknowledged.
+	unacknowledged: HashSet<u64>,
+
+	/// ID of the last abort received from the other computer.
+	handled: Option<u64>,
+}
+
+impl Peer {
+	/// Authenticates and sends a message to the other computer, logging failures.
+	fn send(&mut self, message: &PeerMessage) {
+		let serialized = match postcard::to_allocvec(message) {
+			Ok(serialized) => serialized,
+			Err(error) => {
+				fail!("Failed to serialize message for the other computer: {error}");
+				return;
+			},
+		};
+
+		let counter = ((self.boot_count as u64) << 32) | self.sent;
+		self.sent += 1;
+
+		let datagram = auth::seal_datagram(&self.key, direction(&self.computer), counter, &serialized);
+
+		if let Err(error) = self.socket.send_to(&datagram, self.address) {
+			fail!("Failed to send message to the other computer at {}: {error}", self.address);
+		}
+	}
+
+	/// Checks that a datagram was sent by the other computer and has not been
+	/// accepted before, returning the message it holds.
+	fn open(&mut self, datagram: &[u8]) -> Result<PeerMessage, Rejection> {
+		let (counter, message, tag) = auth::split_datagram(datagram).ok_or(Rejection::Malformed)?;
+
+		if !auth::verify_datagram(&self.key, direction(&other(&self.computer)), counter, message, tag) {
+			return Err(Rejection::Forged);
+		}
+
+		if self.received.is_some_and(|last| counter <= last) {
+			return Err(Rejection::Replayed);
+		}
+
+		self.received = Some(counter);
+
+		// messages are rare enough to persist every one, and the abort is still
+		// handled if persisting fails, since missing an abort is worse
+		if let Err(error) = persistence::save_peer_counter(counter) {
+			fail!("Failed to persist the counter of the other computer: {error}");
+		}
+
+		postcard::from_bytes(message).map_err(Rejection::Undecodable)
+	}
+}
+
+/// Starts listening to the other computer, if one is configured.
+pub fn start(shared: &SharedState, boot_count: u32) -> io::Result<()> {
+	let role = shared.settings.lock().unwrap().role.clone();
+
+	let Some(settings) = role.peer else {
+		return Ok(());
+	};
+
+	let key = fs::read(PEER_KEY_PATH)?;
+	let socket = UdpSocket::bind(("0.0.0.0", settings.port))?;
+	let receiver = socket.try_clone()?;
+
+	*shared.peer.lock().unwrap() = Some(Peer {
+		socket,
+		address: settings.address,
+		key,
+		computer: role.computer,
+		boot_count,
+		sent: 0,
+		received: persistence::load_peer_counter(),
+		unacknowledged: HashSet::new(),
+		handled: None,
+	});
+
+	let shared = shared.clone();
+
+	thread::Builder::new()
+		.name("peer".to_owned())
+		.spawn(move || listen(shared, receiver))?;
+
+	pass!("Coordinating aborts with the other computer at {}.", settings.address);
+	Ok(())
+}
+
+/// Handles every message received from the other computer.
+fn listen(shared: SharedState, receiver: UdpSocket) {
+	let mut buffer = [0; crate::DATA_MESSAGE_BUFFER_SIZE];
+
+	loop {
+		let (length, sender_address) = match receiver.recv_from(&mut buffer) {
+			Ok(received) => received,
+			Err(error) => {
+				fail!("Failed to receive from the other computer: {error}");
+				continue;
+			},
+		};
+
+		let mut guard = shared.peer.lock().unwrap();
+		let Some(peer) = guard.as_mut() else {
+			return;
+		};
+
+		let message = match peer.open(&buffer[..length]) {
+			Ok(message) => message,
+			Err(error) => {
+				warn!("Rejected datagram from {sender_address} claiming to be the other computer: {error}");
+				continue;
+			},
+		};
+
+		match message {
+			PeerMessage::Abort { id, cause } => {
+				peer.send(&PeerMessage::AbortAcknowledged(id));
+
+				// retransmissions of an abort which has already been handled
+				if peer.handled == Some(id) {
+					continue;
+				}
+
+				peer.handled = Some(id);
+				let computer = role::name(&other(&peer.computer));
+
+				// the lock must be released because aborting reads the peer
+				drop(guard);
+				fail!("The {computer} computer aborted due to {cause}.");
+				handler::abort(&shared, AbortCause::Peer(Box::new(cause)));
+			},
+			PeerMessage::AbortAcknowledged(id) => {
+				peer.unacknowledged.remove(&id);
+			},
+		}
+	}
+}
+
+/// Tells the other computer that this one has aborted, retransmitting until it
+/// acknowledges. Aborts caused by the other computer are not sent back to it.
+pub fn coordinate_abort(shared: &SharedState, cause: &AbortCause) {
+	if matches!(cause, AbortCause::Peer(_)) {
+		return;
+	}
+
+	// the ID only has to differ from that of the last abort, so the time will do
+	// if there is no randomness to be had, rather than not telling the other computer
+	let id = auth::random_u64().unwrap_or_else(|error| {
+		warn!("Failed to generate abort ID: {error}. Using the time instead.");
+
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |time| time.as_nanos() as u64)
+	});
+
+	match shared.peer.lock().unwrap().as_mut() {
+		Some(peer) => peer.unacknowledged.insert(id),
+		None => return,
+	};
+
+	let shared = shared.clone();
+	let message = PeerMessage::Abort { id, cause: cause.clone() };
+
+	// retransmitted on its own thread so that the abort itself is not delayed
+	thread::spawn(move || {
+		for _ in 0..PEER_ABORT_ATTEMPTS {
+			let mut guard = shared.peer.lock().unwrap();
+			let Some(peer) = guard.as_mut() else {
+				return;
+			};
+
+			if !peer.unacknowledged.contains(&id) {
+				pass!("The other computer acknowledged the abort.");
+				return;
+			}
+
+			peer.send(&message);
+			drop(guard);
+
+			thread::sleep(PEER_RETRY_INTERVAL);
+		}
+
+		if let Some(peer) = shared.peer.lock().unwrap().as_mut() {
+			if peer.unacknowledged.remove(&id) {
+				fail!("The other computer did not acknowledge the abort after {PEER_ABORT_ATTEMPTS} attempts.");
+			}
+		}
+	});
+}
+
+/// The other computer.
+fn other(computer: &Computer) -> Computer {
+	match computer {
+		Computer::Flight => Computer::Ground,
+		Computer::Ground => Computer::Flight,
+	}
+}
+
+/// The byte mixed into the MAC of every datagram sent by the given computer.
+fn direction(computer: &Computer) -> u8 {
+	match computer {
+		Computer::Flight => b'F',
+		Computer::Ground => b'G',
+	}
+}
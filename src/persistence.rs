@@ -0,0 +1,372 @@
+use common::comm::{BoardId, NodeMapping, Sequence, Trigger};
+use jeflog::{fail, pass};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, fs::{self, File}, io::{self, Write}, path::Path};
+use crate::{forwarder::TelemetryStream, interlock::Interlock, state::SharedState, valve::{MismatchSettings, ValveThresholds}, versioning::{ConfigVersion, ConfigVersions}, PERSISTENCE_DIRECTORY};
+
+/// Name of the file, within `PERSISTENCE_DIRECTORY`, holding the last accepted configuration.
+const CONFIGURATION_FILE: &str = "configuration.postcard";
+
+/// Starts every configuration file, followed by a `ConfigurationFile`.
+const CONFIGURATION_MAGIC: &[u8; 4] = b"FCFG";
+
+/// Incremented whenever the layout of `ConfigurationFile` itself changes.
+const CONFIGURATION_FORMAT: u32 = 1;
+
+/// Name of the file, within `PERSISTENCE_DIRECTORY`, counting how many times the flight computer has started.
+const BOOT_COUNT_FILE: &str = "boot_count";
+
+/// Name of the file, within `PERSISTENCE_DIRECTORY`, holding the counter of the
+/// last datagram accepted from each board.
+const BOARD_COUNTERS_FILE: &str = "board_counters.postcard";
+
+/// Name of the file, within `PERSISTENCE_DIRECTORY`, holding the counter of the
+/// last datagram accepted from the other computer.
+const PEER_COUNTER_FILE: &str = "peer_counter";
+
+/// Everything received from the control server which must survive a restart.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PersistedConfiguration {
+	pub versions: ConfigVersions,
+	pub mappings: Vec<NodeMapping>,
+	pub triggers: Vec<Trigger>,
+	pub abort_sequence: Option<Sequence>,
+	pub safing_sequence: Option<Sequence>,
+	pub valve_thresholds: HashMap<String, ValveThresholds>,
+	pub mismatch_settings: HashMap<String, MismatchSettings>,
+	pub interlocks: Vec<Interlock>,
+	pub telemetry_streams: Vec<TelemetryStream>,
+
+	/// Whether mapping sets containing errors are rejected rather than accepted.
+	pub reject_invalid_mappings: bool,
+}
+
+/// The configuration as laid out on disk, following `CONFIGURATION_MAGIC`.
+///
+/// Every field of `PersistedConfiguration` is serialized separately under its
+/// name, so that adding a field, or changing the type of one, only loses that
+/// field rather than the whole configuration.
+#[derive(Deserialize, Serialize)]
+struct ConfigurationFile {
+	format: u32,
+	fields: Vec<(String, Vec<u8>)>,
+}
+
+/// Loads the last persisted configuration, if there is one.
+///
+/// Any field which cannot be decoded falls back to its default and has its
+/// version reset, so that the server can tell from the flight computer's status
+/// that it must be sent again.
+pub fn load() -> Option<PersistedConfiguration> {
+	let path = Path::new(PERSISTENCE_DIRECTORY).join(CONFIGURATION_FILE);
+
+	let serialized = match fs::read(&path) {
+		Ok(serialized) => serialized,
+		Err(error) if error.kind() == io::ErrorKind::NotFound => return None,
+		Err(error) => {
+			fail!("Failed to read persisted configuration from {}: {error}", path.display());
+			return None;
+		},
+	};
+
+	match deserialize_configuration(&serialized) {
+		Ok((configuration, dropped)) => {
+			if !dropped.is_empty() {
+				fail!("Could not load {dropped:?} from the persisted configuration in {}. They must be sent again by the server.", path.display());
+			}
+
+			Some(configuration)
+		},
+		Err(error) => {
+			fail!("Failed to deserialize persisted configuration from {}: {error}. Everything must be sent again by the server.", path.display());
+			None
+		},
+	}
+}
+
+/// Deserializes a configuration written by `serialize_configuration`, returning
+/// it along with the names of the fields which fell back to their defaults.
+fn deserialize_configuration(serialized: &[u8]) -> io::Result<(PersistedConfiguration, Vec<&'static str>)> {
+	let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+	let sectioned = serialized
+		.strip_prefix(CONFIGURATION_MAGIC)
+		.ok_or_else(|| invalid("not a configuration file".to_owned()))?;
+
+	let file = postcard::from_bytes::<ConfigurationFile>(sectioned)
+		.map_err(|error| invalid(error.to_string()))?;
+
+	if file.format != CONFIGURATION_FORMAT {
+		return Err(invalid(format!("unknown format {}", file.format)));
+	}
+
+	let fields = file.fields.into_iter().collect::<HashMap<_, _>>();
+	let mut dropped = Vec::new();
+
+	let mut configuration = PersistedConfiguration {
+		versions: field(&fields, "versions", &mut dropped),
+		mappings: field(&fields, "mappings", &mut dropped),
+		triggers: field(&fields, "triggers", &mut dropped),
+		abort_sequence: field(&fields, "abort_sequence", &mut dropped),
+		safing_sequence: field(&fields, "safing_sequence", &mut dropped),
+		valve_thresholds: field(&fields, "valve_thresholds", &mut dropped),
+		mismatch_settings: field(&fields, "mismatch_settings", &mut dropped),
+		interlocks: field(&fields, "interlocks", &mut dropped),
+		telemetry_streams: field(&fields, "telemetry_streams", &mut dropped),
+		reject_invalid_mappings: field(&fields, "reject_invalid_mappings", &mut dropped),
+	};
+
+	for name in &dropped {
+		if let Some(version) = version_of(&mut configuration.versions, name) {
+			*version = ConfigVersion::default();
+		}
+	}
+
+	Ok((configuration, dropped))
+}
+
+/// Decodes the named field of a configuration file, falling back to its default
+/// and noting it as dropped if it is missing or cannot be decoded.
+fn field<T: DeserializeOwned + Default>(fields: &HashMap<String, Vec<u8>>, name: &'static str, dropped: &mut Vec<&'static str>) -> T {
+	match fields.get(name).map(|serialized| postcard::from_bytes(serialized)) {
+		Some(Ok(value)) => value,
+		_ => {
+			dropped.push(name);
+			T::default()
+		},
+	}
+}
+
+/// The version of the named field, if it has one.
+fn version_of<'a>(versions: &'a mut ConfigVersions, name: &str) -> Option<&'a mut ConfigVersion> {
+	match name {
+		"mappings" => Some(&mut versions.mappings),
+		"triggers" => Some(&mut versions.triggers),
+		"abort_sequence" => Some(&mut versions.abort_sequence),
+		"safing_sequence" => Some(&mut versions.safing_sequence),
+		"interlocks" => Some(&mut versions.interlocks),
+		"telemetry_streams" => Some(&mut versions.telemetry_streams),
+		"valve_thresholds" => Some(&mut versions.valve_thresholds),
+		"mismatch_settings" => Some(&mut versions.mismatch_settings),
+		_ => None,
+	}
+}
+
+/// Persists the current mappings, triggers, abort and safing sequences, valve settings,
+/// interlocks, telemetry streams, and whether invalid mapping sets are rejected,
+/// along with their versions.
+pub fn save(shared: &SharedState) {
+	let configuration = PersistedConfiguration {
+		versions: *shared.config_versions.lock().unwrap(),
+		mappings: shared.mappings.lock().unwrap().clone(),
+		triggers: shared.triggers.lock().unwrap().clone(),
+		abort_sequence: shared.abort_sequence.lock().unwrap().clone(),
+		safing_sequence: shared.safing_sequence.lock().unwrap().clone(),
+		valve_thresholds: shared.valve_thresholds.lock().unwrap().clone(),
+		mismatch_settings: shared.mismatch_settings.lock().unwrap().clone(),
+		interlocks: shared.interlocks.lock().unwrap().clone(),
+		telemetry_streams: shared.telemetry_streams.lock().unwrap().clone(),
+		reject_invalid_mappings: *shared.reject_invalid_mappings.lock().unwrap(),
+	};
+
+	let result = serialize_configuration(&configuration)
+		.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+		.and_then(|serialized| write_atomically(CONFIGURATION_FILE, &serialized));
+
+	match result {
+		Ok(()) => pass!("Persisted configuration: {:?}.", configuration.versions),
+		Err(error) => fail!("Failed to persist configuration: {error}"),
+	}
+}
+
+/// Serializes the configuration into the format read by `load`.
+fn serialize_configuration(configuration: &PersistedConfiguration) -> postcard::Result<Vec<u8>> {
+	fn serialize<T: Serialize>(name: &str, value: &T) -> postcard::Result<(String, Vec<u8>)> {
+		Ok((name.to_owned(), postcard::to_allocvec(value)?))
+	}
+
+	let file = ConfigurationFile {
+		format: CONFIGURATION_FORMAT,
+		fields: vec![
+			serialize("versions", &configuration.versions)?,
+			serialize("mappings", &configuration.mappings)?,
+			serialize("triggers", &configuration.triggers)?,
+			serialize("abort_sequence", &configuration.abort_sequence)?,
+			serialize("safing_sequence", &configuration.safing_sequence)?,
+			serialize("valve_thresholds", &configuration.valve_thresholds)?,
+			serialize("mismatch_settings", &configuration.mismatch_settings)?,
+			serialize("interlocks", &configuration.interlocks)?,
+			serialize("telemetry_streams", &configuration.telemetry_streams)?,
+			serialize("reject_invalid_mappings", &configuration.reject_invalid_mappings)?,
+		],
+	};
+
+	postcard::to_extend(&file, CONFIGURATION_MAGIC.to_vec())
+}
+
+/// Increments and returns the number of times the flight computer has started.
+///
+/// Fails if the incremented count cannot be persisted, since it would then be
+/// returned again after the next start and counters sent under it would repeat.
+pub fn next_boot_count() -> io::Result<u32> {
+	let path = Path::new(PERSISTENCE_DIRECTORY).join(BOOT_COUNT_FILE);
+
+	let previous = fs::read(&path)
+		.ok()
+		.and_then(|bytes| bytes.try_into().ok())
+		.map_or(0, u32::from_be_bytes);
+
+	let boot_count = previous.wrapping_add(1);
+	write_atomically(BOOT_COUNT_FILE, &boot_count.to_be_bytes())?;
+	Ok(boot_count)
+}
+
+/// Loads the counter of the last datagram accepted from each board before the
+/// flight computer last stopped, so that none of them can be replayed.
+pub fn load_board_counters() -> HashMap<BoardId, u64> {
+	let path = Path::new(PERSISTENCE_DIRECTORY).join(BOARD_COUNTERS_FILE);
+
+	let serialized = match fs::read(&path) {
+		Ok(serialized) => serialized,
+		Err(error) if error.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+		Err(error) => {
+			fail!("Failed to read board counters from {}: {error}", path.display());
+			return HashMap::new();
+		},
+	};
+
+	postcard::from_bytes(&serialized).unwrap_or_else(|error| {
+		fail!("Failed to deserialize board counters from {}: {error}", path.display());
+		HashMap::new()
+	})
+}
+
+/// Persists the counter of the last datagram accepted from each board.
+pub fn save_board_counters(counters: &HashMap<BoardId, u64>) -> io::Result<()> {
+	let serialized = postcard::to_allocvec(counters)
+		.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+	write_atomically(BOARD_COUNTERS_FILE, &serialized)
+}
+
+/// Loads the counter of the last datagram accepted from the other computer, if
+/// one has ever been accepted.
+pub fn load_peer_counter() -> Option<u64> {
+	let path = Path::new(PERSISTENCE_DIRECTORY).join(PEER_COUNTER_FILE);
+
+	fs::read(path)
+		.ok()
+		.and_then(|bytes| bytes.try_into().ok())
+		.map(u64::from_be_bytes)
+}
+
+/// Persists the counter of the last datagram accepted from the other computer.
+pub fn save_peer_counter(counter: u64) -> io::Result<()> {
+	write_atomically(PEER_COUNTER_FILE, &counter.to_be_bytes())
+}
+
+/// Writes the bytes to a temporary file and renames it over the named file, so
+/// that a reboot part-way through never leaves a partially written file behind.
+fn write_atomically(name: &str, serialized: &[u8]) -> io::Result<()> {
+	let directory = Path::new(PERSISTENCE_DIRECTORY);
+	let temporary = directory.join(format!("{name}.tmp"));
+
+	fs::create_dir_all(directory)?;
+
+	let mut file = File::create(&temporary)?;
+	file.write_all(serialized)?;
+	file.sync_all()?;
+
+	fs::rename(&temporary, directory.join(name))?;
+
+	// sync the directory as well so that the rename itself is durable
+	File::open(directory)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::ValveState;
+	use std::time::Duration;
+	use crate::{encoding::TelemetryEncoding, interlock::InterlockCondition, valve::MismatchAction};
+
+	/// A configuration with a value in every field but the mappings and triggers.
+	fn configuration() -> PersistedConfiguration {
+		let mut configuration = PersistedConfiguration {
+			abort_sequence: Some(Sequence { name: "abort".to_owned(), script: "valve.close()".to_owned() }),
+			valve_thresholds: HashMap::from([("valve".to_owned(), ValveThresholds { voltage_hysteresis: 0.5, ..Default::default() })]),
+			mismatch_settings: HashMap::from([("valve".to_owned(), MismatchSettings { response_time: Duration::from_millis(200), action: MismatchAction::Retry(2) })]),
+			interlocks: vec![Interlock {
+				name: "no_fuel_without_ox".to_owned(),
+				valve: "fuel".to_owned(),
+				state: ValveState::Open,
+				condition: InterlockCondition::ValveClosed("ox".to_owned()),
+			}],
+			telemetry_streams: vec![TelemetryStream {
+				name: "radio".to_owned(),
+				destination: None,
+				period: Duration::from_millis(200),
+				subscription: Some(vec!["pt_1".to_owned()]),
+				encoding: TelemetryEncoding::Delta { keyframe_interval: 10, compress: true },
+			}],
+			reject_invalid_mappings: true,
+			..Default::default()
+		};
+
+		configuration.versions.abort_sequence.update(&configuration.abort_sequence);
+		configuration.versions.interlocks.update(&configuration.interlocks);
+		configuration.versions.telemetry_streams.update(&configuration.telemetry_streams);
+		configuration
+	}
+
+	#[test]
+	fn configuration_round_trips() {
+		let configuration = configuration();
+		let serialized = serialize_configuration(&configuration).unwrap();
+		let (loaded, dropped) = deserialize_configuration(&serialized).unwrap();
+
+		assert!(dropped.is_empty());
+		assert_eq!(loaded.versions, configuration.versions);
+		assert_eq!(loaded.abort_sequence, configuration.abort_sequence);
+		assert_eq!(loaded.valve_thresholds, configuration.valve_thresholds);
+		assert_eq!(loaded.mismatch_settings, configuration.mismatch_settings);
+		assert_eq!(loaded.telemetry_streams, configuration.telemetry_streams);
+		assert!(loaded.reject_invalid_mappings);
+		assert_eq!(serialize_configuration(&loaded).unwrap(), serialized);
+	}
+
+	#[test]
+	fn undecodable_field_falls_back_alone() {
+		let configuration = configuration();
+		let serialized = serialize_configuration(&configuration).unwrap();
+
+		let mut file = postcard::from_bytes::<ConfigurationFile>(&serialized[CONFIGURATION_MAGIC.len()..]).unwrap();
+
+		for (name, value) in &mut file.fields {
+			if name == "interlocks" {
+				*value = vec![0xFF; 4];
+			}
+		}
+
+		let corrupted = postcard::to_extend(&file, CONFIGURATION_MAGIC.to_vec()).unwrap();
+		let (loaded, dropped) = deserialize_configuration(&corrupted).unwrap();
+
+		assert_eq!(dropped, ["interlocks"]);
+		assert!(loaded.interlocks.is_empty());
+		assert_eq!(loaded.versions.interlocks, ConfigVersion::default());
+
+		// everything else, versions included, is kept
+		assert_eq!(loaded.versions.telemetry_streams, configuration.versions.telemetry_streams);
+		assert_eq!(loaded.telemetry_streams, configuration.telemetry_streams);
+		assert_eq!(loaded.abort_sequence, configuration.abort_sequence);
+	}
+
+	#[test]
+	fn unknown_format_is_rejected() {
+		let file = ConfigurationFile { format: CONFIGURATION_FORMAT + 1, fields: Vec::new() };
+		let serialized = postcard::to_extend(&file, CONFIGURATION_MAGIC.to_vec()).unwrap();
+
+		assert!(deserialize_configuration(&serialized).is_err());
+		assert!(deserialize_configuration(b"not a configuration").is_err());
+	}
+}
@@ -0,0 +1,239 @@
+use jeflog::fail;
+use common::comm::{BoardId, NodeMapping, ValveState};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+use crate::{abort::AbortEvent, forwarder::TelemetryStream, arming::ArmState, auth::FrameWriter, control::ServerRole, discovery::DiscoveryMethod, interlock::{Interlock, InterlockViolation}, scheduler::{ActuationTiming, Schedule}, switchboard::CommandQueueMetrics, tare::TareTarget, validation::MappingIssue, valve::{MismatchSettings, ValveThresholds}, versioning::ConfigVersions};
+
+/// Leading byte which marks a control frame as a flight-specific extension
+/// rather than a `FlightControlMessage`.
+///
+/// A postcard-encoded `FlightControlMessage` begins with its variant index as a
+/// varint, which can never start with this byte given how few variants exist.
+pub const EXTENSION_TAG: u8 = 0xFF;
+
+/// Operator commands understood by the flight computer in addition to the
+/// `FlightControlMessage`s defined in `common`.
+///
+/// These are sent on the control connection as `EXTENSION_TAG` followed by the
+/// postcard-encoded command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum OperatorCommand {
+	/// Clears the abort latch, allowing sequences to be run again.
+	ResetAbort,
+
+	/// Requests every retained abort event, answered with `FlightReport::AbortHistory`.
+	QueryAborts,
+
+	/// Requests the versions of the current configuration, answered with
+	/// `FlightReport::Configuration`.
+	QueryConfiguration,
+
+	/// Sets whether a mapping set containing errors is rejected outright rather
+	/// than accepted with the errors reported.
+	RejectInvalidMappings(bool),
+
+	/// Adds, replaces, and removes individual mappings by `text_id`, as a
+	/// single atomic change validated like a full mapping set.
+	UpdateMappings {
+		/// Mappings to add, or to replace the existing mapping with the same `text_id`.
+		upsert: Vec<NodeMapping>,
+
+		/// The `text_id`s of mappings to remove.
+		remove: Vec<String>,
+	},
+
+	/// Averages the targeted mappings over the window and adjusts their calibrated
+	/// offsets so that they read zero, answered with `FlightReport::TareOffsets`.
+	Tare {
+		target: TareTarget,
+		window: Duration,
+	},
+
+	/// Sets the thresholds used to estimate the state of a valve, by `text_id`.
+	/// `None` restores the default thresholds.
+	SetValveThresholds {
+		text_id: String,
+		thresholds: Option<ValveThresholds>,
+	},
+
+	/// Sets how a valve's commanded and actual states are compared, by `text_id`.
+	/// `None` restores the default settings.
+	SetMismatchSettings {
+		text_id: String,
+		settings: Option<MismatchSettings>,
+	},
+
+	/// Requests a confirmation token for arming, answered with `FlightReport::ArmToken`.
+	RequestArm,
+
+	/// Arms the vehicle, given the token most recently issued by `RequestArm`.
+	Arm {
+		token: u64,
+	},
+
+	/// Returns the vehicle from armed to safe.
+	Disarm,
+
+	/// Replaces every interlock. Valve commands which would violate one are
+	/// rejected and answered with `FlightReport::InterlockViolation`.
+	SetInterlocks(Vec<Interlock>),
+
+	/// Passes command authority to the other connected control server. Only
+	/// accepted from the server currently holding authority.
+	HandOverAuthority,
+
+	/// Actuates valves at precise offsets from now, answered with
+	/// `FlightReport::ScheduleComplete`.
+	ScheduleValves(Schedule),
+
+	/// Replaces every telemetry stream. An empty list restores the single
+	/// full-rate stream to every connected server.
+	SetTelemetryStreams(Vec<TelemetryStream>),
+
+	/// Keeps the control connection alive. Accepted from any server and
+	/// otherwise ignored.
+	Heartbeat,
+}
+
+/// Reports sent from the flight computer to the control server, framed the
+/// same way as `OperatorCommand`s.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum FlightReport {
+	/// An abort was requested. Sent for every request, including those made
+	/// while an abort is already in progress.
+	Abort(AbortEvent),
+
+	/// The retained abort events, oldest first.
+	AbortHistory(Vec<AbortEvent>),
+
+	/// Which control server holds command authority, sent whenever it changes
+	/// and to every server upon connecting.
+	Authority(Option<ServerRole>),
+
+	/// How the control server was found, sent upon connecting.
+	ServerDiscovered(DiscoveryMethod),
+
+	/// The versions of the current configuration, sent upon connecting so the
+	/// server can decide whether to resend mappings, triggers, and the abort sequence.
+	Configuration(ConfigVersions),
+
+	/// The result of validating an uploaded mapping set.
+	MappingValidation {
+		/// Whether the mapping set replaced the previous one.
+		accepted: bool,
+		issues: Vec<MappingIssue>,
+	},
+
+	/// The new calibrated offsets of tared mappings, by `text_id`, for the server to persist.
+	TareOffsets(Vec<(String, f64)>),
+
+	/// A valve did not reach its commanded state within its response time.
+	ValveMismatch {
+		text_id: String,
+		commanded: ValveState,
+		actual: ValveState,
+	},
+
+	/// A mismatched valve, by `text_id`, has reached its commanded state.
+	ValveMismatchCleared(String),
+
+	/// A valve command was never confirmed by the valve's current readback,
+	/// despite being retransmitted.
+	CommandFailed {
+		board_id: BoardId,
+		channel: u32,
+		powered: bool,
+	},
+
+	/// An authenticated identity message arrived from a new address while the
+	/// board was still alive at its known address, which was kept.
+	BoardAddressConflict {
+		board_id: BoardId,
+		known: SocketAddr,
+		new: SocketAddr,
+	},
+
+	/// The token which must be sent back with `OperatorCommand::Arm` to arm the vehicle.
+	ArmToken(u64),
+
+	/// The most recently issued arm token expired before it was sent back.
+	ArmTokenExpired,
+
+	/// The arm state has changed.
+	ArmState(ArmState),
+
+	/// A valve command was rejected because it would violate an interlock.
+	InterlockViolation(InterlockViolation),
+
+	/// A sequence started by the operator has finished running, by name.
+	SequenceFinished(String),
+
+	/// A board was heard from for the first time since being declared dead,
+	/// or was declared dead after not being heard from.
+	BoardStatus {
+		board_id: BoardId,
+		alive: bool,
+	},
+
+	/// A valve schedule has finished, or was stopped early, with the time at
+	/// which each actuation was actually sent.
+	ScheduleComplete {
+		name: String,
+		timings: Vec<ActuationTiming>,
+	},
+}
+
+/// Periodic status of the flight computer, sent alongside vehicle state telemetry.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlightStatus {
+	/// The versions of the configuration currently in use.
+	pub configuration: ConfigVersions,
+
+	/// The state of each board's command queue.
+	pub command_queues: HashMap<BoardId, CommandQueueMetrics>,
+
+	/// Whether the vehicle may currently be commanded to do anything hazardous.
+	pub arm_state: ArmState,
+
+	/// Which control server holds command authority.
+	pub authority: Option<ServerRole>,
+}
+
+/// Sends a report to every connected control server.
+///
+/// Failures are logged rather than returned, since there is nothing more
+/// useful the caller could do with them.
+pub fn send_report(control_sockets: &Mutex<HashMap<ServerRole, FrameWriter>>, report: &FlightReport) {
+	let Some(frame) = serialize_report(report) else {
+		return;
+	};
+
+	for (role, socket) in control_sockets.lock().unwrap().iter_mut() {
+		if let Err(error) = socket.send(&frame) {
+			fail!("Failed to send flight report to the {role} control server: {error}");
+		}
+	}
+}
+
+/// Sends a report to a single control server, such as one which is not yet
+/// among the connected servers.
+pub fn send_report_to(control_socket: &mut FrameWriter, report: &FlightReport) {
+	let Some(frame) = serialize_report(report) else {
+		return;
+	};
+
+	if let Err(error) = control_socket.send(&frame) {
+		fail!("Failed to send flight report to control server: {error}");
+	}
+}
+
+/// Serializes a report as `EXTENSION_TAG` followed by the postcard-encoded report.
+fn serialize_report(report: &FlightReport) -> Option<Vec<u8>> {
+	match postcard::to_extend(report, vec![EXTENSION_TAG]) {
+		Ok(frame) => Some(frame),
+		Err(error) => {
+			fail!("Failed to serialize flight report: {error}");
+			None
+		},
+	}
+}
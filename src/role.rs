@@ -0,0 +1,51 @@
+use common::comm::{BoardId, Computer};
+use serde::{Deserialize, Serialize};
+use crate::{peer::PeerSettings, FC_BOARD_ID, GC_BOARD_ID};
+
+/// Which computer this is and what it is responsible for.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoleSettings {
+	/// Whether this is the flight computer, which owns the boards on the
+	/// vehicle, or the ground computer, which owns the ground support boards.
+	/// Written as `"Flight"` or `"Ground"`.
+	///
+	/// There is no default, since a ground computer mistaking itself for the
+	/// flight computer would command the vehicle's boards.
+	pub computer: Computer,
+
+	/// The boards this computer owns, or `None` to own every board it has a key
+	/// for. Anything received from any other board is ignored.
+	#[serde(default)]
+	pub boards: Option<Vec<BoardId>>,
+
+	/// How to reach the other computer to coordinate aborts with it, or `None`
+	/// if this computer runs alone.
+	#[serde(default)]
+	pub peer: Option<PeerSettings>,
+}
+
+impl RoleSettings {
+	/// Whether this computer is responsible for the given board.
+	pub fn owns(&self, board_id: &BoardId) -> bool {
+		match &self.boards {
+			Some(boards) => boards.contains(board_id),
+			None => true,
+		}
+	}
+
+	/// The board ID this computer identifies itself to boards with.
+	pub fn board_id(&self) -> &'static str {
+		match self.computer {
+			Computer::Flight => FC_BOARD_ID,
+			Computer::Ground => GC_BOARD_ID,
+		}
+	}
+}
+
+/// The name of a computer, for logging.
+pub fn name(computer: &Computer) -> &'static str {
+	match computer {
+		Computer::Flight => "flight",
+		Computer::Ground => "ground",
+	}
+}
@@ -0,0 +1,150 @@
+use common::comm::ValveState;
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{hint, sync::mpsc::Receiver, thread, time::{Duration, Instant}};
+use crate::{control, handler, protocol::{self, FlightReport}, state::SharedState, CommandSender, ABORT_SEQUENCE_NAME, COMMAND_CONFIRMATION_TIMEOUT, COMMAND_QUEUE_EXPIRY, SCHEDULE_SPIN_THRESHOLD};
+
+/// A single valve actuation within a schedule.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScheduledActuation {
+	/// When to actuate the valve, relative to the start of the schedule.
+	pub offset: Duration,
+
+	/// The `text_id` of the valve.
+	pub valve: String,
+
+	pub state: ValveState,
+}
+
+/// A named batch of valve actuations to be executed with precise relative timing.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Schedule {
+	pub name: String,
+	pub actuations: Vec<ScheduledActuation>,
+}
+
+/// When a scheduled actuation was actually sent to its board.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ActuationTiming {
+	pub valve: String,
+	pub state: ValveState,
+
+	/// When the actuation was scheduled, relative to the start of the schedule.
+	pub scheduled: Duration,
+
+	/// When the commander sent the actuation to its board, relative to the start
+	/// of the schedule, or `None` if it was never sent, e.g. because the board
+	/// was unreachable until the command expired.
+	pub actual: Option<Duration>,
+}
+
+/// Executes a schedule on the calling thread, reporting the actual send times
+/// to the server and returning them.
+///
+/// If the schedule was started by a sequence, it stops early when that sequence is
+/// stopped. Otherwise, it stops early if the flight computer aborts. Either way,
+/// it stops early if the vehicle is not armed or an actuation is rejected by an interlock.
+pub fn run(shared: &SharedState, command_tx: &CommandSender, mut schedule: Schedule, sequence: Option<&str>) -> Vec<ActuationTiming> {
+	// stable, so actuations with the same offset keep the order they were given in
+	schedule.actuations.sort_by_key(|actuation| actuation.offset);
+
+	let mut dispatched: Vec<(ScheduledActuation, Receiver<Instant>)> = Vec::with_capacity(schedule.actuations.len());
+	let start = Instant::now();
+
+	for actuation in schedule.actuations {
+		wait_until(start + actuation.offset);
+
+		let stopped = match sequence {
+			Some(name) => !shared.sequences.lock().unwrap().contains_left(name),
+			None => shared.abort.lock().unwrap().aborted,
+		};
+
+		if stopped {
+			warn!("Stopping schedule '{}' early because it was interrupted.", schedule.name);
+			break;
+		}
+
+		let arm_state = shared.arming.lock().unwrap().state;
+
+		let abort_sequence = sequence == Some(ABORT_SEQUENCE_NAME);
+		let safing_sequence = sequence.is_some_and(|name| control::is_safing_sequence(shared, name));
+
+		if !arm_state.permits_actuation(abort_sequence, safing_sequence) {
+			warn!("Stopping schedule '{}' early because the vehicle is {arm_state}.", schedule.name);
+			break;
+		}
+
+		// the rest of a schedule is unlikely to be safe without the rejected actuation
+		match handler::actuate_valve(shared, &actuation.valve, actuation.state, command_tx, abort_sequence || safing_sequence) {
+			Ok(sent) => dispatched.push((actuation, sent)),
+			Err(_) => {
+				warn!("Stopping schedule '{}' early because an actuation violated an interlock.", schedule.name);
+				break;
+			},
+		}
+	}
+
+	// collected afterwards so that waiting on the commander cannot delay later actuations.
+	// a command is sent or dropped within its expiry, so this only times out if the commander is stuck
+	let timings = dispatched
+		.into_iter()
+		.map(|(actuation, sent)| ActuationTiming {
+			actual: sent
+				.recv_timeout(COMMAND_QUEUE_EXPIRY + COMMAND_CONFIRMATION_TIMEOUT)
+				.ok()
+				.map(|sent| sent.saturating_duration_since(start)),
+			valve: actuation.valve,
+			state: actuation.state,
+			scheduled: actuation.offset,
+		})
+		.collect::<Vec<_>>();
+
+	for timing in &timings {
+		match timing.actual {
+			Some(actual) => {
+				let late = actual.saturating_sub(timing.scheduled);
+				pass!("Schedule '{}' actuated '{}' to {} at {actual:?} ({late:?} late).", schedule.name, timing.valve, timing.state);
+			},
+			None => fail!("Schedule '{}' never sent the command to actuate '{}' to {}.", schedule.name, timing.valve, timing.state),
+		}
+	}
+
+	protocol::send_report(&shared.control_sockets, &FlightReport::ScheduleComplete { name: schedule.name, timings: timings.clone() });
+	timings
+}
+
+/// Starts a schedule requested by the server on its own thread.
+pub fn spawn(shared: &SharedState, schedule: Schedule) {
+	if shared.abort.lock().unwrap().aborted {
+		fail!("Refusing to run schedule '{}' because the flight computer has aborted. The abort must be reset first.", schedule.name);
+		return;
+	}
+
+	let Some(command_tx) = shared.command_tx.lock().unwrap().clone() else {
+		fail!("Cannot run schedule '{}' because the switchboard has not started.", schedule.name);
+		return;
+	};
+
+	let shared = shared.clone();
+
+	let result = thread::Builder::new()
+		.name(format!("schedule-{}", schedule.name))
+		.spawn(move || run(&shared, &command_tx, schedule, None));
+
+	if let Err(error) = result {
+		fail!("Failed to spawn schedule thread: {error}");
+	}
+}
+
+/// Sleeps until shortly before the deadline, then spins for the remainder.
+fn wait_until(deadline: Instant) {
+	let remaining = deadline.saturating_duration_since(Instant::now());
+
+	if remaining > SCHEDULE_SPIN_THRESHOLD {
+		thread::sleep(remaining - SCHEDULE_SPIN_THRESHOLD);
+	}
+
+	while Instant::now() < deadline {
+		hint::spin_loop();
+	}
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io};
+use crate::{control::ControlSettings, discovery::DiscoverySettings, role::RoleSettings, SETTINGS_PATH};
+
+/// Settings local to this computer, read from `SETTINGS_PATH` at startup.
+///
+/// Unlike the configuration received from the control server, these are needed
+/// before the server is found, so they are kept in a file edited by hand. Every
+/// field but `role.computer` is optional, falling back to its default.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Settings {
+	/// How to discover the primary control server.
+	#[serde(default)]
+	pub discovery: DiscoverySettings,
+
+	/// How to discover the backup control server, or `None` if there is no backup.
+	#[serde(default)]
+	pub backup: Option<DiscoverySettings>,
+
+	/// How the control connections are monitored and what to do if they are lost.
+	#[serde(default)]
+	pub control: ControlSettings,
+
+	/// Which computer this is, which boards it owns, and how to reach the other computer.
+	pub role: RoleSettings,
+}
+
+/// Loads the settings, failing if the file is missing or invalid, since there
+/// is no safe default for which computer this is.
+pub fn load() -> io::Result<Settings> {
+	let contents = fs::read_to_string(SETTINGS_PATH)?;
+
+	let settings = toml::from_str::<Settings>(&contents)
+		.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+	settings.control.warn_if_unmonitored();
+	Ok(settings)
+}
+
+/// Serializes a `Duration` as a whole number of milliseconds, which is easier to
+/// write by hand than the default representation.
+pub mod milliseconds {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::time::Duration;
+
+	pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_u64(duration.as_millis() as u64)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+		u64::deserialize(deserializer).map(Duration::from_millis)
+	}
+}
+
+#[cfg(test)]
+impl Settings {
+	/// The settings of a flight computer which leaves everything else at its default.
+	pub fn flight() -> Self {
+		toml::from_str("[role]\ncomputer = \"Flight\"").unwrap()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::Computer;
+	use std::time::Duration;
+	use crate::{control::LossOfGroundPolicy, DEFAULT_HEARTBEAT_TIMEOUT};
+
+	#[test]
+	fn role_is_required() {
+		assert!(toml::from_str::<Settings>("").is_err());
+		assert!(toml::from_str::<Settings>("[role]\nboards = [\"sam-01\"]").is_err());
+	}
+
+	#[test]
+	fn everything_else_falls_back_to_defaults() {
+		let settings = toml::from_str::<Settings>("[role]\ncomputer = \"Ground\"").unwrap();
+
+		assert!(matches!(settings.role.computer, Computer::Ground));
+		assert!(settings.role.boards.is_none());
+		assert!(settings.backup.is_none());
+		assert_eq!(settings.control.heartbeat_timeout, Some(DEFAULT_HEARTBEAT_TIMEOUT));
+		assert!(matches!(settings.control.loss_of_ground, LossOfGroundPolicy::Continue));
+	}
+
+	#[test]
+	fn zero_heartbeat_timeout_disables_it() {
+		let settings = toml::from_str::<Settings>("[control]\nheartbeat_timeout = 0\n\n[role]\ncomputer = \"Flight\"").unwrap();
+		assert_eq!(settings.control.heartbeat_timeout, None);
+
+		let settings = toml::from_str::<Settings>("[control]\nheartbeat_timeout = 2500\n\n[role]\ncomputer = \"Flight\"").unwrap();
+		assert_eq!(settings.control.heartbeat_timeout, Some(Duration::from_millis(2500)));
+	}
+}
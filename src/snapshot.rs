@@ -0,0 +1,75 @@
+use common::comm::VehicleState;
+use serde::Serialize;
+use std::{collections::HashMap, mem};
+
+/// Copies the vehicle state into a snapshot kept across calls.
+///
+/// Once the snapshot has been taken, taking it again only overwrites values in
+/// place, so it allocates nothing and the caller holds the vehicle state lock
+/// only for as long as the copy takes.
+pub fn copy_state(snapshot: &mut VehicleState, source: &VehicleState) {
+	copy_map(&mut snapshot.valve_states, &source.valve_states);
+	copy_map(&mut snapshot.sensor_readings, &source.sensor_readings);
+}
+
+/// Makes `snapshot` equal to `source`, cloning the whole map only when its keys have changed.
+pub fn copy_map<V: Clone>(snapshot: &mut HashMap<String, V>, source: &HashMap<String, V>) {
+	// a clone shares the hasher and layout of its source, so as long as no keys
+	// have been added or removed since, both iterate in the same order and the
+	// values can be copied pairwise without hashing a single key
+	if snapshot.len() == source.len() {
+		let mut pairs = snapshot.iter_mut().zip(source);
+
+		let matched = pairs.all(|((key, value), (source_key, source_value))| {
+			if key != source_key {
+				return false;
+			}
+
+			value.clone_from(source_value);
+			true
+		});
+
+		if matched {
+			return;
+		}
+	}
+
+	*snapshot = source.clone();
+}
+
+/// Copies only the named valves and sensors of the vehicle state into a snapshot
+/// kept across calls, which must only ever be given the same names.
+pub fn copy_subscribed(snapshot: &mut VehicleState, source: &VehicleState, subscription: &[String]) {
+	copy_subscribed_map(&mut snapshot.valve_states, &source.valve_states, subscription);
+	copy_subscribed_map(&mut snapshot.sensor_readings, &source.sensor_readings, subscription);
+}
+
+/// Copies only the named entries of the map into a snapshot kept across calls,
+/// which must only ever be given the same names.
+pub fn copy_subscribed_map<V: Clone>(snapshot: &mut HashMap<String, V>, source: &HashMap<String, V>, subscription: &[String]) {
+	for name in subscription {
+		copy_entry(snapshot, source, name);
+	}
+}
+
+/// Makes the snapshot's entry for the name equal to the source's, if it has one.
+fn copy_entry<V: Clone>(snapshot: &mut HashMap<String, V>, source: &HashMap<String, V>, name: &String) {
+	let Some(value) = source.get(name) else {
+		snapshot.remove(name);
+		return;
+	};
+
+	if let Some(existing) = snapshot.get_mut(name) {
+		existing.clone_from(value);
+	} else {
+		snapshot.insert(name.clone(), value.clone());
+	}
+}
+
+/// Serializes a value with Postcard into a buffer kept across calls, replacing
+/// its contents. The buffer only reallocates when the value outgrows it.
+pub fn serialize_into<T: Serialize>(value: &T, buffer: &mut Vec<u8>) -> postcard::Result<()> {
+	buffer.clear();
+	*buffer = postcard::to_extend(value, mem::take(buffer))?;
+	Ok(())
+}
@@ -1,23 +1,91 @@
-use common::{comm::{Computer, FlightControlMessage, NodeMapping, Sequence, VehicleState}, sequence};
+use common::{comm::{BoardId, FlightControlMessage, NodeMapping, Sequence, VehicleState}, sequence};
 use jeflog::{task, pass, warn, fail};
-use postcard::experimental::max_size::MaxSize;
-use std::{fmt, io::{self, Read, Write}, net::{IpAddr, TcpStream, UdpSocket}, sync::{Arc, Mutex}, thread::{self, ThreadId}, time::Duration};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt, net::{IpAddr, UdpSocket}, sync::{mpsc, Arc, Mutex}, thread::{self, ThreadId}, time::Duration};
 use bimap::BiHashMap;
-use crate::{forwarder, handler::{self, create_device_handler}, switchboard, SWITCHBOARD_ADDRESS, SERVO_PORT};
+use crate::{abort::{AbortCause, AbortLatch}, arming::{self, Arming}, auth::FrameWriter, builtins, control::{self, ControlEvent, ServerRole}, discovery::BeaconListener, event::{self, Event, EventLoop, EventSender, Timer}, forwarder::{self, TelemetryStream}, handler::{self, create_device_handler}, interlock::Interlock, peer::{self, Peer}, persistence::{self, PersistedConfiguration}, protocol::{self, FlightReport, OperatorCommand, EXTENSION_TAG}, scheduler, settings::{self, Settings}, switchboard::{self, CommandQueueMetrics}, tare::{self, TareAccumulator}, validation::{validate_mappings, Severity}, valve::{MismatchSettings, ValveThresholds, ValveTrackers}, versioning::ConfigVersions, CommandSender, ABORT_SEQUENCE_NAME, ARM_TOKEN_LIFETIME, INIT_RETRY_DELAY, SETTINGS_PATH, SWITCHBOARD_ADDRESS, TELEMETRY_MIN_PERIOD, TRIGGER_SEQUENCE_PREFIX};
 use pyo3::Python;
 
 /// Holds all shared state that should be accessible concurrently in multiple contexts.
 /// 
 /// Everything in this struct should be wrapped with `Arc<Mutex<T>>`. **Do not abuse this struct.**
 /// It is intended for what would typically be global state.
+///
+/// Fields held at the same time are locked in the order the worker locks them:
+/// `vehicle_state`, `mappings`, `interlocks`, `tares`, `valve_thresholds`,
+/// `mismatch_settings`, `acquisition_times`, then `valve_trackers`. Any other
+/// field is locked on its own or last. Every lock must be released before calling
+/// `persistence::save`, which locks each persisted field in turn, and before
+/// `protocol::send_report`, which can block on a slow server.
+///
+/// `sequences` is held while a sequence's thread is spawned until it has been
+/// registered, so that the thread cannot act, or finish and unregister itself,
+/// before then.
 #[derive(Clone, Debug)]
 pub struct SharedState {
 	pub vehicle_state: Arc<Mutex<VehicleState>>,
+	pub acquisition_times: Arc<Mutex<HashMap<String, f64>>>,
 	pub mappings: Arc<Mutex<Vec<NodeMapping>>>,
-	pub server_address: Arc<Mutex<Option<IpAddr>>>,
+	pub server_addresses: Arc<Mutex<HashMap<ServerRole, IpAddr>>>,
+	pub beacon_listeners: Arc<Mutex<HashMap<u16, BeaconListener>>>,
 	pub triggers: Arc<Mutex<Vec<common::comm::Trigger>>>,
 	pub sequences: Arc<Mutex<BiHashMap<String, ThreadId>>>,
 	pub abort_sequence: Arc<Mutex<Option<Sequence>>>,
+	pub safing_sequence: Arc<Mutex<Option<Sequence>>>,
+	pub abort: Arc<Mutex<AbortLatch>>,
+	pub control_sockets: Arc<Mutex<HashMap<ServerRole, FrameWriter>>>,
+	pub config_versions: Arc<Mutex<ConfigVersions>>,
+	pub reject_invalid_mappings: Arc<Mutex<bool>>,
+	pub tares: Arc<Mutex<HashMap<String, TareAccumulator>>>,
+	pub valve_thresholds: Arc<Mutex<HashMap<String, ValveThresholds>>>,
+	pub mismatch_settings: Arc<Mutex<HashMap<String, MismatchSettings>>>,
+	pub valve_trackers: Arc<Mutex<ValveTrackers>>,
+	pub valve_mismatches: Arc<Mutex<HashSet<String>>>,
+	pub command_queues: Arc<Mutex<HashMap<BoardId, CommandQueueMetrics>>>,
+	pub command_tx: Arc<Mutex<Option<CommandSender>>>,
+	pub interlocks: Arc<Mutex<Vec<Interlock>>>,
+	pub telemetry_streams: Arc<Mutex<Vec<TelemetryStream>>>,
+	pub arming: Arc<Mutex<Arming>>,
+	pub settings: Arc<Mutex<Settings>>,
+	pub authority: Arc<Mutex<Option<ServerRole>>>,
+	pub events: Arc<Mutex<EventSender>>,
+	pub peer: Arc<Mutex<Option<Peer>>>,
+}
+
+
+impl SharedState {
+	/// Creates the shared state from the persisted configuration and this computer's settings,
+	/// with nothing yet connected or running.
+	pub fn new(persisted: PersistedConfiguration, settings: Settings, events: EventSender) -> Self {
+		SharedState {
+			vehicle_state: Arc::new(Mutex::new(VehicleState::new())),
+			acquisition_times: Arc::new(Mutex::new(HashMap::new())),
+			mappings: Arc::new(Mutex::new(persisted.mappings)),
+			server_addresses: Arc::new(Mutex::new(HashMap::new())),
+			beacon_listeners: Arc::new(Mutex::new(HashMap::new())),
+			triggers: Arc::new(Mutex::new(persisted.triggers)),
+			sequences: Arc::new(Mutex::new(BiHashMap::new())),
+			abort_sequence: Arc::new(Mutex::new(persisted.abort_sequence)),
+			safing_sequence: Arc::new(Mutex::new(persisted.safing_sequence)),
+			abort: Arc::new(Mutex::new(AbortLatch::default())),
+			control_sockets: Arc::new(Mutex::new(HashMap::new())),
+			config_versions: Arc::new(Mutex::new(persisted.versions)),
+			reject_invalid_mappings: Arc::new(Mutex::new(persisted.reject_invalid_mappings)),
+			tares: Arc::new(Mutex::new(HashMap::new())),
+			valve_thresholds: Arc::new(Mutex::new(persisted.valve_thresholds)),
+			mismatch_settings: Arc::new(Mutex::new(persisted.mismatch_settings)),
+			valve_trackers: Arc::new(Mutex::new(ValveTrackers::default())),
+			valve_mismatches: Arc::new(Mutex::new(HashSet::new())),
+			command_queues: Arc::new(Mutex::new(HashMap::new())),
+			command_tx: Arc::new(Mutex::new(None)),
+			interlocks: Arc::new(Mutex::new(persisted.interlocks)),
+			telemetry_streams: Arc::new(Mutex::new(persisted.telemetry_streams)),
+			arming: Arc::new(Mutex::new(Arming::default())),
+			settings: Arc::new(Mutex::new(settings)),
+			authority: Arc::new(Mutex::new(None)),
+			events: Arc::new(Mutex::new(events)),
+			peer: Arc::new(Mutex::new(None)),
+		}
+	}
 }
 
 
@@ -27,9 +95,12 @@ pub enum ProgramState {
 	/// and transitions to the `ServerDiscovery` state.
 	Init,
 	
-	/// State which loops through potential server hostnames until locating the
-	/// server and connecting to it via TCP.
+	/// State which waits until a control server has been located and connected
+	/// to, which happens whenever no server is connected.
 	ServerDiscovery {
+		/// Everything that happens on the control connections and elsewhere in the flight computer.
+		events: EventLoop,
+
 		/// The shared flight state.
 		shared: SharedState,
 	},
@@ -37,7 +108,8 @@ pub enum ProgramState {
 	/// State which waits for an operator command, such as setting mappings or
 	/// running a sequence.
 	WaitForOperator {
-		server_socket: TcpStream,
+		/// Everything that happens on the control connections and elsewhere in the flight computer.
+		events: EventLoop,
 
 		/// The shared flight state.
 		shared: SharedState,
@@ -46,7 +118,8 @@ pub enum ProgramState {
 	/// State which spawns a thread to run a sequence before returning to the
 	/// `WaitForOperator` state.
 	RunSequence {
-		server_socket: TcpStream,
+		/// Everything that happens on the control connections and elsewhere in the flight computer.
+		events: EventLoop,
 
 		/// A full description of the sequence to run.
 		sequence: Sequence,
@@ -61,9 +134,9 @@ impl ProgramState {
 	pub fn next(self) -> Self {
 		match self {
 			ProgramState::Init => init(),
-			ProgramState::ServerDiscovery { shared } => server_discovery(shared),
-			ProgramState::WaitForOperator { server_socket, shared } => wait_for_operator(server_socket, shared),
-			ProgramState::RunSequence { server_socket, sequence, shared } => run_sequence(server_socket, sequence, shared),
+			ProgramState::ServerDiscovery { events, shared } => server_discovery(events, shared),
+			ProgramState::WaitForOperator { events, shared } => wait_for_operator(events, shared),
+			ProgramState::RunSequence { events, sequence, shared } => run_sequence(events, sequence, shared),
 		}
 	}
 }
@@ -73,13 +146,14 @@ impl fmt::Display for ProgramState {
 		match self {
 			Self::Init => write!(f, "Init"),
 			Self::ServerDiscovery { .. } => write!(f, "ServerDiscovery"),
-			Self::WaitForOperator { server_socket, .. } => {
-				let peer_address = server_socket
-					.peer_addr()
-					.map(|addr| addr.to_string())
-					.unwrap_or("unknown".to_owned());
-
-				write!(f, "WaitForOperator(server = {peer_address})")
+			Self::WaitForOperator { shared, .. } => {
+				let authority = shared.authority
+					.lock()
+					.unwrap()
+					.map(|role| role.to_string())
+					.unwrap_or("none".to_owned());
+
+				write!(f, "WaitForOperator(authority = {authority})")
 			},
 			Self::RunSequence { sequence, .. } => {
 				write!(f, "RunSequence(name = {})", sequence.name)
@@ -89,210 +163,555 @@ impl fmt::Display for ProgramState {
 }
 
 fn init() -> ProgramState {
+	// which computer this is decides which boards it talks to, so nothing starts until it is known
+	let settings = match settings::load() {
+		Ok(settings) => settings,
+		Err(error) => {
+			fail!("Failed to load settings from {SETTINGS_PATH}: {error}. Retrying in {INIT_RETRY_DELAY:?}.");
+			thread::sleep(INIT_RETRY_DELAY);
+			return ProgramState::Init;
+		},
+	};
+
 	let home_socket = UdpSocket::bind(SWITCHBOARD_ADDRESS)
 		.expect(&format!("Cannot create bind on address {:#?}", SWITCHBOARD_ADDRESS));
 
-	let shared = SharedState {
-		vehicle_state: Arc::new(Mutex::new(VehicleState::new())),
-		mappings: Arc::new(Mutex::new(Vec::new())),
-		server_address: Arc::new(Mutex::new(None)),
-		triggers: Arc::new(Mutex::new(Vec::new())),
-		sequences: Arc::new(Mutex::new(BiHashMap::new())),
-		abort_sequence: Arc::new(Mutex::new(None)),
+	let (events_tx, events) = mpsc::channel();
+
+	let persisted = persistence::load().unwrap_or_else(|| {
+		warn!("No persisted configuration found. Waiting for the server to send one.");
+		PersistedConfiguration::default()
+	});
+
+	if persisted.versions != ConfigVersions::default() {
+		pass!("Loaded persisted configuration: {:?}.", persisted.versions);
+	}
+
+	let shared = SharedState::new(persisted, settings, events_tx.clone());
+
+	// the boot count keeps counters sent to boards and the other computer from repeating across restarts
+	let boot_count = match persistence::next_boot_count() {
+		Ok(boot_count) => boot_count,
+		Err(error) => {
+			fail!("Failed to persist boot count: {error}. Retrying in {INIT_RETRY_DELAY:?}.");
+			thread::sleep(INIT_RETRY_DELAY);
+			return ProgramState::Init;
+		},
 	};
 
 	let command_tx = 
-		match switchboard::start(shared.clone(), home_socket) {
+		match switchboard::start(shared.clone(), home_socket, boot_count) {
 			Ok(command_tx) => command_tx,
 			Err(error) => {
-				fail!("Failed to create switchboard: {error}");
+				fail!("Failed to create switchboard: {error}. Retrying in {INIT_RETRY_DELAY:?}.");
+				thread::sleep(INIT_RETRY_DELAY);
 				return ProgramState::Init;
 			}
 	};
 
+	*shared.command_tx.lock().unwrap() = Some(command_tx.clone());
+
 	sequence::initialize(shared.mappings.clone());
 	sequence::set_device_handler(create_device_handler(shared.clone(), command_tx));
 
+	if let Err(error) = builtins::register(&shared) {
+		fail!("Failed to register flight builtins for sequences: {error}");
+	}
+
+	if let Err(error) = peer::start(&shared, boot_count) {
+		fail!("Failed to start coordinating with the other computer: {error}. Aborts will not be coordinated.");
+	}
+
 	thread::spawn(check_triggers(&shared));
+	thread::spawn(forwarder::forward_vehicle_state(&shared));
+
+	let settings = shared.settings.lock().unwrap().clone();
+
+	thread::spawn(control::maintain_connection(&shared, ServerRole::Primary, settings.discovery, events_tx.clone()));
 
-	ProgramState::ServerDiscovery { shared }
+	if let Some(backup) = settings.backup {
+		thread::spawn(control::maintain_connection(&shared, ServerRole::Backup, backup, events_tx));
+	}
+
+	ProgramState::ServerDiscovery { events: EventLoop::new(events), shared }
 }
 
-fn server_discovery(shared: SharedState) -> ProgramState {
-	task!("Locating control server.");
+/// Handles events until one of the threads maintaining the control connections
+/// has connected to a server.
+fn server_discovery(mut events: EventLoop, shared: SharedState) -> ProgramState {
+	task!("Waiting for a control server to connect.");
 
-	let potential_hostnames = ["server-01.local", "server-02.local", "localhost"];
+	loop {
+		match events.next() {
+			Some(Event::Control(ControlEvent::Connected(role))) => {
+				control::server_connected(&shared, role);
+				return ProgramState::WaitForOperator { events, shared };
+			},
+			Some(Event::Control(ControlEvent::Disconnected(role))) => {
+				control::server_disconnected(&shared, role);
+			},
+			Some(Event::Control(ControlEvent::Frame(role, _))) => {
+				warn!("Ignoring frame from the {role} server received before it connected.");
+			},
+			Some(event) => handle_internal_event(event, &shared),
+			None => {
+				fail!("Every thread sending events has exited. No control server can be connected to.");
+				thread::sleep(Duration::from_secs(1));
+			},
+		}
+	}
+}
 
-	for host in potential_hostnames {
-		task!("Attempting to connect to \x1b[1m{}:{SERVO_PORT}\x1b[0m.", host);
+/// Waits for the next event, which is usually an operator command but may be
+/// anything else the main loop reacts to.
+fn wait_for_operator(mut events: EventLoop, shared: SharedState) -> ProgramState {
+	let Some(event) = events.next() else {
+		return ProgramState::ServerDiscovery { events, shared };
+	};
 
-		let Ok(mut stream) = TcpStream::connect((host, SERVO_PORT)) else {
-			fail!("Failed to connect to \x1b[1m{}:{SERVO_PORT}\x1b[0m.", host);
-			continue;
+	match event {
+		Event::Control(ControlEvent::Connected(role)) => {
+			control::server_connected(&shared, role);
+			ProgramState::WaitForOperator { events, shared }
+		},
+		Event::Control(ControlEvent::Disconnected(role)) => {
+			control::server_disconnected(&shared, role);
+
+			if shared.control_sockets.lock().unwrap().is_empty() {
+				control::lose_ground(&shared);
+				ProgramState::ServerDiscovery { events, shared }
+			} else {
+				ProgramState::WaitForOperator { events, shared }
+			}
+		},
+		Event::Control(ControlEvent::Frame(role, buffer)) => handle_frame(role, buffer, events, shared),
+		event => {
+			handle_internal_event(event, &shared);
+			ProgramState::WaitForOperator { events, shared }
+		},
+	}
+}
+
+/// Handles an event which did not come from a control connection, which is
+/// handled the same way whether or not a server is connected.
+fn handle_internal_event(event: Event, shared: &SharedState) {
+	match event {
+		// control events depend on the state, so they are handled by each state
+		Event::Control(_) => {},
+		Event::SequenceFinished(name) => {
+			pass!("Sequence '{name}' finished.");
+
+			let safing_sequence = control::safing_sequence_name(shared);
+
+			let firing = shared.sequences
+				.lock()
+				.unwrap()
+				.left_values()
+				.any(|running| arming::keeps_firing(running, safing_sequence.as_deref()));
+
+			// an abort has already moved the vehicle out of firing, so this cannot undo it
+			if !firing {
+				arming::finish_firing(shared);
+			}
+
+			protocol::send_report(&shared.control_sockets, &FlightReport::SequenceFinished(name));
+		},
+		Event::BoardStatus { board_id, alive } => {
+			protocol::send_report(&shared.control_sockets, &FlightReport::BoardStatus { board_id, alive });
+		},
+		Event::Aborted(event) => {
+			protocol::send_report(&shared.control_sockets, &FlightReport::Abort(event));
+		},
+		Event::ArmState(state) => {
+			protocol::send_report(&shared.control_sockets, &FlightReport::ArmState(state));
+		},
+		Event::Timer(Timer::ArmTokenExpiry(issued)) => {
+			arming::expire_token(shared, issued);
+		},
+	}
+}
+
+/// Handles a frame received from a control server, ignoring anything but an
+/// abort, a query, or a heartbeat from a server which does not hold command authority.
+fn handle_frame(role: ServerRole, buffer: Vec<u8>, events: EventLoop, shared: SharedState) -> ProgramState {
+	let authoritative = *shared.authority.lock().unwrap() == Some(role);
+
+	if buffer.first() == Some(&EXTENSION_TAG) {
+		return match postcard::from_bytes::<OperatorCommand>(&buffer[1..]) {
+			Ok(command) => handle_operator_command(command, role, authoritative, events, shared),
+			Err(error) => {
+				warn!("Failed to deserialize operator command: {}.", error.to_string());
+				ProgramState::WaitForOperator { events, shared }
+			},
 		};
+	}
 
-		pass!("Successfully connected to \x1b[1m{}:{SERVO_PORT}\x1b[0m.", host);
-		pass!("Found control server at \x1b[1m{}:{SERVO_PORT}\x1b[0m.", host);
+	match postcard::from_bytes::<FlightControlMessage>(&buffer) {
+		Ok(message) => {
+			if !authoritative && !matches!(message, FlightControlMessage::Abort) {
+				warn!("Ignoring control message from the {role} server because it does not hold command authority.");
+				return ProgramState::WaitForOperator { events, shared };
+			}
 
-		let hostname = hostname::get()
-			.ok()
-			.and_then(|name| name.into_string().ok());
-	
-		let computer;
+			match message {
+				FlightControlMessage::Mappings(mappings) => {
+					pass!("Received mappings from server: {mappings:#?}");
+					set_mappings(&shared, |_| mappings);
+					ProgramState::WaitForOperator { events, shared }
+				},
+				FlightControlMessage::Sequence(sequence) => {
+					pass!("Received sequence from server: {sequence:#?}");
+
+					// if the abort sequence was set, don't run it
+					// set the shared abort sequence and return early
+					if sequence.name == ABORT_SEQUENCE_NAME {
+						let abort_sequence = Some(sequence);
+						shared.config_versions.lock().unwrap().abort_sequence.update(&abort_sequence);
+						*shared.abort_sequence.lock().unwrap() = abort_sequence;
+						persistence::save(&shared);
+						return ProgramState::WaitForOperator { events, shared };
+					}
 
-		if let Some(hostname) = hostname {
-			if hostname.starts_with("flight") {
-				computer = Computer::Flight;
-			} else if hostname.starts_with("ground") {
-				computer = Computer::Ground;
-			} else {
-				warn!("Local hostname does not start with 'flight' or 'ground'. Defaulting to flight.");
-				computer = Computer::Flight;
+					// likewise, the safing sequence is only run upon losing the ground
+					if control::is_safing_sequence(&shared, &sequence.name) {
+						let safing_sequence = Some(sequence);
+						shared.config_versions.lock().unwrap().safing_sequence.update(&safing_sequence);
+						*shared.safing_sequence.lock().unwrap() = safing_sequence;
+						persistence::save(&shared);
+						return ProgramState::WaitForOperator { events, shared };
+					}
+
+					ProgramState::RunSequence { events, sequence, shared }
+				},
+				FlightControlMessage::Trigger(trigger) => {
+					pass!("Received trigger from server: {trigger:#?}");
+					
+					// update existing trigger if one has the same name
+					// otherwise, add a new trigger to the vec
+					let mut triggers = shared.triggers.lock().unwrap();
+
+					let existing = triggers
+						.iter()
+						.position(|t| t.name == trigger.name);
+
+					if let Some(index) = existing {
+						triggers[index] = trigger;
+					} else {
+						triggers.push(trigger);
+					}
+
+					shared.config_versions.lock().unwrap().triggers.update(&*triggers);
+
+					// necessary to allow passing 'shared' back to WaitForOperator
+					drop(triggers);
+					persistence::save(&shared);
+
+					ProgramState::WaitForOperator { events, shared }
+				},
+				FlightControlMessage::StopSequence(name) => {
+					pass!("Received instruction to stop sequence from server.");
+					let stopped = shared.sequences
+						.lock()
+						.unwrap()
+						.remove_by_left(&name);
+
+					if stopped.is_some() {
+						pass!("Stopped sequence '{name}'.");
+					} else {
+						warn!("Sequence '{name}' was not running.");
+					}
+
+					ProgramState::WaitForOperator { events, shared }
+				},
+				FlightControlMessage::Abort => {
+					pass!("Received abort instruction from server.");
+					handler::abort(&shared, AbortCause::Operator);
+					ProgramState::WaitForOperator { events, shared }
+				}
 			}
-		} else {
-			warn!("Failed to get local hostname. Defaulting to flight.");
-			computer = Computer::Flight;
+		},
+		Err(error) => {
+			warn!("Failed to deserialize control message: {}.", error.to_string());
+			ProgramState::WaitForOperator { events, shared }
 		}
+	}
+}
 
-		// buffer containing the serialized identity message to be sent to the control server
-		let mut identity = [0; Computer::POSTCARD_MAX_SIZE];
+/// Builds a new mapping set from the current one, validates it, reporting any
+/// issues to the server, and replaces the current mappings with it unless it is
+/// rejected.
+///
+/// The vehicle state and mappings are locked for the whole update, so the worker
+/// never sees a partially applied mapping set or readings from removed mappings.
+fn set_mappings(shared: &SharedState, update: impl FnOnce(&[NodeMapping]) -> Vec<NodeMapping>) {
+	let mut vehicle_state = shared.vehicle_state.lock().unwrap();
+	let mut mappings = shared.mappings.lock().unwrap();
+
+	let updated = update(&mappings);
+	let issues = validate_mappings(&updated);
+	let has_errors = issues.iter().any(|issue| issue.severity == Severity::Error);
+	let accepted = !has_errors || !*shared.reject_invalid_mappings.lock().unwrap();
+
+	for issue in &issues {
+		warn!("{issue}");
+	}
 
-		if let Err(error) = postcard::to_slice(&computer, &mut identity) {
-			fail!("Failed to serialize Computer: {error}");
-			continue;
-		}
+	if accepted {
+		let mut acquisition_times = shared.acquisition_times.lock().unwrap();
+		let mut valve_trackers = shared.valve_trackers.lock().unwrap();
+
+		// remove readings, valve states, and valve trackers which no mapping will update anymore
+		for mapping in mappings.iter() {
+			if updated.iter().any(|m| m.text_id == mapping.text_id) {
+				continue;
+			}
+
+			let text_id = &mapping.text_id;
 
-		if let Err(error) = stream.write_all(&identity) {
-			warn!("Failed to send identity message to control server: {error}");
-			continue;
+			for name in [text_id.clone(), format!("{text_id}_V"), format!("{text_id}_I")] {
+				vehicle_state.sensor_readings.remove(&name);
+				acquisition_times.remove(&name);
+			}
+
+			vehicle_state.valve_states.remove(text_id);
+			valve_trackers.estimators.remove(text_id);
+			valve_trackers.monitors.remove(text_id);
 		}
 
-		*shared.server_address.lock().unwrap() = Some(stream.peer_addr().unwrap().ip());
-		thread::spawn(forwarder::forward_vehicle_state(&shared));
+		drop(valve_trackers);
+		drop(acquisition_times);
+
+		shared.config_versions.lock().unwrap().mappings.update(&updated);
+		*mappings = updated;
 
-		return ProgramState::WaitForOperator { server_socket: stream, shared };
+		drop(mappings);
+		drop(vehicle_state);
+		persistence::save(shared);
+	} else {
+		drop(mappings);
+		drop(vehicle_state);
+		fail!("Rejected mapping set because it contains errors. Keeping the previous mappings.");
 	}
 
-	fail!("Failed to locate control server at all potential hostnames. Retrying.");
-	ProgramState::ServerDiscovery { shared }
+	// sent with no locks held, so that a slow server cannot stall the worker
+	protocol::send_report(&shared.control_sockets, &FlightReport::MappingValidation { accepted, issues });
 }
 
-fn wait_for_operator(mut server_socket: TcpStream, shared: SharedState) -> ProgramState {
-	let mut buffer = vec![0; 1_000_000];
+/// Handles a flight-specific operator command, returning to `WaitForOperator`.
+fn handle_operator_command(command: OperatorCommand, role: ServerRole, authoritative: bool, mut events: EventLoop, shared: SharedState) -> ProgramState {
+	if !authoritative && !matches!(command, OperatorCommand::Heartbeat | OperatorCommand::QueryAborts | OperatorCommand::QueryConfiguration) {
+		warn!("Ignoring operator command from the {role} server because it does not hold command authority.");
+		return ProgramState::WaitForOperator { events, shared };
+	}
 
-	match server_socket.read(&mut buffer) {
-		Ok(size) => {
-			// if the size is zero, a TCP shutdown packet was sent. the connection is closed.
-			if size == 0 {
-				return ProgramState::ServerDiscovery { shared };
-			}
+	match command {
+		OperatorCommand::Heartbeat => {},
+		OperatorCommand::HandOverAuthority => {
+			pass!("Received instruction to hand over command authority from the {role} server.");
+			control::hand_over(&shared, role);
+		},
+		OperatorCommand::ResetAbort => {
+			pass!("Received instruction to reset abort latch from server.");
+			handler::reset_abort(&shared);
+		},
+		OperatorCommand::QueryAborts => {
+			pass!("Received query for abort history from server.");
+
+			let history = shared.abort
+				.lock()
+				.unwrap()
+				.history
+				.iter()
+				.cloned()
+				.collect();
+
+			protocol::send_report(&shared.control_sockets, &FlightReport::AbortHistory(history));
+		},
+		OperatorCommand::QueryConfiguration => {
+			pass!("Received query for configuration versions from server.");
+			let versions = *shared.config_versions.lock().unwrap();
+			protocol::send_report(&shared.control_sockets, &FlightReport::Configuration(versions));
+		},
+		OperatorCommand::RejectInvalidMappings(reject) => {
+			pass!("Received instruction to {} invalid mapping sets from server.", if reject { "reject" } else { "accept" });
+			*shared.reject_invalid_mappings.lock().unwrap() = reject;
+			persistence::save(&shared);
+		},
+		OperatorCommand::UpdateMappings { upsert, remove } => {
+			pass!("Received mapping updates from server: {upsert:#?}, removing {remove:?}.");
+
+			set_mappings(&shared, |current| {
+				let mut updated = current.to_vec();
 
-			match postcard::from_bytes::<FlightControlMessage>(&buffer) {
-				Ok(message) => {
-					match message {
-						FlightControlMessage::Mappings(mappings) => {
-							pass!("Received mappings from server: {mappings:#?}");
-							*shared.mappings.lock().unwrap() = mappings;
-							ProgramState::WaitForOperator { server_socket, shared }
-						},
-						FlightControlMessage::Sequence(sequence) => {
-							pass!("Received sequence from server: {sequence:#?}");
-
-							// if the abort sequence was set, don't run it
-							// set the shared abort sequence and return early
-							if sequence.name == "abort" {
-								*shared.abort_sequence.lock().unwrap() = Some(sequence);
-								return ProgramState::WaitForOperator { server_socket, shared };
-							}
-
-							ProgramState::RunSequence { server_socket, sequence, shared }
-						},
-						FlightControlMessage::Trigger(trigger) => {
-							pass!("Received trigger from server: {trigger:#?}");
-							
-							// update existing trigger if one has the same name
-							// otherwise, add a new trigger to the vec
-							let mut triggers = shared.triggers.lock().unwrap();
-
-							let existing = triggers
-								.iter()
-								.position(|t| t.name == trigger.name);
-
-							if let Some(index) = existing {
-								triggers[index] = trigger;
-							} else {
-								triggers.push(trigger);
-							}
-
-							// necessary to allow passing 'shared' back to WaitForOperator
-							drop(triggers);
-
-							ProgramState::WaitForOperator { server_socket, shared }
-						},
-						FlightControlMessage::StopSequence(name) => {
-							pass!("Received instruction to stop sequence from server.");
-							let stopped = shared.sequences
-								.lock()
-								.unwrap()
-								.remove_by_left(&name);
-
-							if stopped.is_some() {
-								pass!("Stopped sequence '{name}'.");
-							} else {
-								warn!("Sequence '{name}' was not running.");
-							}
-
-							ProgramState::WaitForOperator { server_socket, shared }
-						},
-						FlightControlMessage::Abort => {
-							pass!("Received abort instruction from server.");
-							handler::abort(&shared);
-							ProgramState::WaitForOperator { server_socket, shared }
-						}
+				for text_id in &remove {
+					if !updated.iter().any(|mapping| mapping.text_id == *text_id) {
+						warn!("Cannot remove mapping '{text_id}' because it is not defined.");
 					}
-				},
-				Err(error) => {
-					warn!("Failed to deserialize control message: {}.", error.to_string());
-					ProgramState::WaitForOperator { server_socket, shared }
+
+					updated.retain(|mapping| mapping.text_id != *text_id);
 				}
+
+				for mapping in upsert {
+					if let Some(existing) = updated.iter_mut().find(|m| m.text_id == mapping.text_id) {
+						*existing = mapping;
+					} else {
+						updated.push(mapping);
+					}
+				}
+
+				updated
+			});
+		},
+		OperatorCommand::Tare { target, window } => {
+			pass!("Received instruction to tare {target:?} over {window:?} from server.");
+			let shared = shared.clone();
+			thread::spawn(move || tare::tare(shared, target, window));
+		},
+		OperatorCommand::SetValveThresholds { text_id, thresholds } => {
+			pass!("Received valve thresholds for '{text_id}' from server: {thresholds:#?}");
+			let mut valve_thresholds = shared.valve_thresholds.lock().unwrap();
+
+			if let Some(thresholds) = thresholds {
+				valve_thresholds.insert(text_id, thresholds);
+			} else {
+				valve_thresholds.remove(&text_id);
 			}
+
+			// sorted so that the hash does not depend on the order the map iterates in
+			let sorted = valve_thresholds.iter().collect::<BTreeMap<_, _>>();
+			shared.config_versions.lock().unwrap().valve_thresholds.update(&sorted);
+
+			drop(valve_thresholds);
+			persistence::save(&shared);
 		},
-		Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
-			ProgramState::WaitForOperator { server_socket, shared }
+		OperatorCommand::SetMismatchSettings { text_id, settings } => {
+			pass!("Received mismatch settings for '{text_id}' from server: {settings:#?}");
+			let mut mismatch_settings = shared.mismatch_settings.lock().unwrap();
+
+			if let Some(settings) = settings {
+				mismatch_settings.insert(text_id, settings);
+			} else {
+				mismatch_settings.remove(&text_id);
+			}
+
+			let sorted = mismatch_settings.iter().collect::<BTreeMap<_, _>>();
+			shared.config_versions.lock().unwrap().mismatch_settings.update(&sorted);
+
+			drop(mismatch_settings);
+			persistence::save(&shared);
+		},
+		OperatorCommand::RequestArm => {
+			pass!("Received request for an arm token from server.");
+
+			if let Some(issued) = arming::request_arm(&shared) {
+				events.schedule(ARM_TOKEN_LIFETIME, Timer::ArmTokenExpiry(issued));
+			}
+		},
+		OperatorCommand::Arm { token } => {
+			pass!("Received instruction to arm from server.");
+			arming::arm(&shared, token);
+		},
+		OperatorCommand::Disarm => {
+			pass!("Received instruction to disarm from server.");
+			arming::disarm(&shared);
+		},
+		OperatorCommand::SetInterlocks(interlocks) => {
+			pass!("Received interlocks from server: {interlocks:#?}");
+			let mut current = shared.interlocks.lock().unwrap();
+
+			*current = interlocks;
+			shared.config_versions.lock().unwrap().interlocks.update(&*current);
+
+			drop(current);
+			persistence::save(&shared);
+		},
+		OperatorCommand::SetTelemetryStreams(streams) => {
+			pass!("Received telemetry streams from server: {streams:#?}");
+
+			if let Some(stream) = streams.iter().find(|stream| stream.period < TELEMETRY_MIN_PERIOD) {
+				fail!("Rejected telemetry streams because '{}' is sent more often than every {TELEMETRY_MIN_PERIOD:?}. Keeping the previous streams.", stream.name);
+				return ProgramState::WaitForOperator { events, shared };
+			}
+
+			let mut current = shared.telemetry_streams.lock().unwrap();
+
+			*current = streams;
+			shared.config_versions.lock().unwrap().telemetry_streams.update(&*current);
+
+			drop(current);
+			persistence::save(&shared);
+		},
+		OperatorCommand::ScheduleValves(schedule) => {
+			pass!("Received valve schedule from server: {schedule:#?}");
+			scheduler::spawn(&shared, schedule);
 		},
-		Err(error) => {
-			fail!("Failed to read from server socket: {}. Dropping connection.", error.to_string());
-			ProgramState::ServerDiscovery { shared }
-		}
 	}
+
+	ProgramState::WaitForOperator { events, shared }
 }
 
 /// Spawns a thread which runs the specified sequence before returning to `WaitForOperator`.
-fn run_sequence(server_socket: TcpStream, sequence: Sequence, shared: SharedState) -> ProgramState {
+fn run_sequence(events: EventLoop, sequence: Sequence, shared: SharedState) -> ProgramState {
+	if shared.abort.lock().unwrap().aborted {
+		fail!("Refusing to run sequence '{}' because the flight computer has aborted. The abort must be reset first.", sequence.name);
+		return ProgramState::WaitForOperator { events, shared };
+	}
+
 	let sequence_name = sequence.name.clone();
+	arming::start_firing(&shared);
+
+	let mut sequences = shared.sequences.lock().unwrap();
+	let thread_shared = shared.clone();
+	let finished_name = sequence_name.clone();
+
+	let thread_id = thread::spawn(move || {
+		sequence::run(sequence);
+
+		thread_shared.sequences
+			.lock()
+			.unwrap()
+			.remove_by_right(&thread::current().id());
 
-	let thread_id = thread::spawn(|| sequence::run(sequence))
+		event::notify(&thread_shared, Event::SequenceFinished(finished_name));
+	})
 		.thread()
 		.id();
 
-	shared.sequences
-		.lock()
-		.unwrap()
-		.insert(sequence_name, thread_id);
+	sequences.insert(sequence_name, thread_id);
+	drop(sequences);
 
-	ProgramState::WaitForOperator { server_socket, shared }
+	ProgramState::WaitForOperator { events, shared }
 }
 
 /// Constructs a closure which continuously checks if any triggers have tripped,
 /// running the corresponding script inline if so.
 fn check_triggers(shared: &SharedState) -> impl FnOnce() -> () {
-	let triggers = shared.triggers.clone();
+	let shared_triggers = shared.triggers.clone();
+	let config_versions = shared.config_versions.clone();
+	let sequences = shared.sequences.clone();
+	let abort = shared.abort.clone();
 
 	// return closure instead of using the function itself because of borrow-checking
 	// rules regarding moving the 'triggers' reference across closure bounds
 	move || {
+		let mut triggers = Vec::new();
+		let mut triggers_version = None;
+
 		loop {
-			let mut triggers = triggers.lock().unwrap();
+			// triggers are not evaluated again until the abort latch is reset
+			if abort.lock().unwrap().aborted {
+				thread::sleep(Duration::from_millis(10));
+				continue;
+			}
+
+			// the triggers are checked from a copy, taken whenever the server changes
+			// them, so that they are never locked while a trigger's sequence runs
+			let version = config_versions.lock().unwrap().triggers;
+
+			if triggers_version != Some(version) {
+				triggers = shared_triggers.lock().unwrap().clone();
+				triggers_version = Some(version);
+			}
 
 			for trigger in triggers.iter_mut() {
 				// perform check by running condition as Python script and getting truth value
@@ -306,25 +725,64 @@ fn check_triggers(shared: &SharedState) -> impl FnOnce() -> () {
 				// checks if the condition evaluated true
 				if check.as_ref().is_ok_and(|c| *c) {
 					let sequence = Sequence {
-						name: format!("trigger_{}", trigger.name),
+						name: format!("{TRIGGER_SEQUENCE_PREFIX}{}", trigger.name),
 						script: trigger.script.clone(),
 					};
 
+					// register this thread as running the trigger's sequence so the
+					// device handler accepts its actions, as with any other sequence
+					let thread_id = thread::current().id();
+					sequences.lock().unwrap().insert(sequence.name.clone(), thread_id);
+
 					// run sequence in the same thread so there is no rapid-fire
 					// sequence dispatches if a trigger is tripped
 					// note: this is intentionally blocking
 					common::sequence::run(sequence);
+
+					sequences.lock().unwrap().remove_by_right(&thread_id);
 				}
 
 				if let Err(error) = check {
 					fail!("Trigger '{}' raised exception during execution: {error}", trigger.name);
 					trigger.active = false;
+
+					// the shared trigger is the one persisted and reported to the server
+					let mut shared_triggers = shared_triggers.lock().unwrap();
+
+					if let Some(shared_trigger) = shared_triggers.iter_mut().find(|t| t.name == trigger.name) {
+						shared_trigger.active = false;
+					}
 				}
 			}
 
-			// drop triggers before waiting so the lock isn't held over the wait
-			drop(triggers);
 			thread::sleep(Duration::from_millis(10));
 		}
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::arming::ArmState;
+
+	#[test]
+	fn firing_ends_when_only_background_sequences_remain() {
+		let shared = SharedState::new(PersistedConfiguration::default(), Settings::flight(), mpsc::channel().0);
+		let here = thread::current().id();
+		let elsewhere = thread::spawn(|| {}).thread().id();
+
+		arming::transition(&shared, ArmState::Armed);
+		arming::start_firing(&shared);
+
+		// an abort sequence left over from an abort which has since been reset
+		shared.sequences.lock().unwrap().insert(ABORT_SEQUENCE_NAME.to_owned(), here);
+		shared.sequences.lock().unwrap().insert("burn".to_owned(), elsewhere);
+
+		handle_internal_event(Event::SequenceFinished("chill".to_owned()), &shared);
+		assert_eq!(shared.arming.lock().unwrap().state, ArmState::Firing);
+
+		shared.sequences.lock().unwrap().remove_by_left("burn");
+		handle_internal_event(Event::SequenceFinished("burn".to_owned()), &shared);
+		assert_eq!(shared.arming.lock().unwrap().state, ArmState::Armed);
+	}
+}
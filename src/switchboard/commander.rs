@@ -1,47 +1,303 @@
-use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, sync::{mpsc::Receiver, Arc, RwLock}};
-use common::comm::{BoardId, SamControlMessage};
-use jeflog::{fail, pass};
-use crate::{handler, state::SharedState, COMMAND_MESSAGE_BUFFER_SIZE, SAM_PORT};
+use std::{collections::{HashMap, VecDeque}, net::{SocketAddr, UdpSocket}, sync::{mpsc::{Receiver, RecvTimeoutError, Sender}, Arc, Mutex, RwLock}, time::Instant};
+use common::comm::{BoardId, SamControlMessage, SensorType};
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use super::keyring::Keyring;
+use crate::{abort::AbortCause, handler, protocol::{self, FlightReport}, state::SharedState, COMMAND_CONFIRMATION_TIMEOUT, COMMAND_MAX_ATTEMPTS, COMMAND_MESSAGE_BUFFER_SIZE, COMMAND_QUEUE_CAPACITY, COMMAND_QUEUE_EXPIRY, SAM_PORT};
 
-/// "fast lane" for sending SamControlMessages. Only wakes up when there's a command to be sent.
-pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMessage)>, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() -> () {
+/// A valve command which has been sent but not yet confirmed by the valve's current readback.
+struct PendingCommand {
+  powered: bool,
+  attempts: u32,
+  sent_at: Instant,
+}
+
+/// Counters describing a single board's command queue.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct CommandQueueMetrics {
+  /// Commands waiting to be sent.
+  pub depth: usize,
+
+  /// Commands sent since startup, not counting retransmissions.
+  pub sent: u64,
+
+  /// Commands dropped since startup because they expired, overflowed the queue,
+  /// or could not be serialized.
+  pub dropped: u64,
+}
+
+/// A command waiting to be sent to a board.
+struct QueuedCommand {
+  command: SamControlMessage,
+  queued_at: Instant,
+
+  /// Where to report when the command is actually sent.
+  sent: Sender<Instant>,
+}
+
+/// Commands waiting to be sent to a single board, in the order they were issued.
+#[derive(Default)]
+struct CommandQueue {
+  commands: VecDeque<QueuedCommand>,
+  metrics: CommandQueueMetrics,
+}
+
+impl CommandQueue {
+  /// Queues a command, returning the oldest command if it had to be dropped to make room.
+  fn push(&mut self, queued: QueuedCommand) -> Option<SamControlMessage> {
+    let dropped = if self.commands.len() >= COMMAND_QUEUE_CAPACITY {
+      self.metrics.dropped += 1;
+      self.commands.pop_front().map(|dropped| dropped.command)
+    } else {
+      None
+    };
+
+    self.commands.push_back(queued);
+    dropped
+  }
+}
+
+/// Outcome of trying to send a single command to a board.
+enum Delivery {
+  Sent,
+
+  /// The board has not identified itself yet or the socket failed, so the command should be tried again later.
+  Unreachable,
+
+  /// The command can never be sent, so it should be dropped.
+  Invalid,
+}
+
+/// "fast lane" for sending SamControlMessages. Only wakes up when there's a command to be sent,
+/// or when a sent valve command needs to be confirmed or retransmitted.
+///
+/// Each board has its own queue, so commands to a board which has not identified itself yet
+/// are buffered (until they expire) without holding up commands to any other board.
+pub fn commander(shared: SharedState, commands: Receiver<(BoardId, SamControlMessage, Sender<Instant>)>, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, keyring: Arc<Mutex<Keyring>>) -> impl FnOnce() -> () {
   move || {
     let mut buffer = [0; COMMAND_MESSAGE_BUFFER_SIZE];
+    let mut queues: HashMap<BoardId, CommandQueue> = HashMap::new();
+
+    // keyed by board and channel, so a newer command to the same valve replaces an older one
+    let mut pending: HashMap<(BoardId, u32), PendingCommand> = HashMap::new();
+
+    loop {
+      match commands.recv_timeout(COMMAND_CONFIRMATION_TIMEOUT / 4) {
+        Ok((board_id, command, sent)) => {
+          if !sockets.read().unwrap().contains_key(&board_id) {
+            warn!("{board_id} has not identified itself yet. Buffering command until it does.");
+          }
+
+          if let Some(dropped) = queues.entry(board_id.clone()).or_default().push(QueuedCommand { command, queued_at: Instant::now(), sent }) {
+            fail!("Command queue for {board_id} is full. Dropping oldest command: {dropped:?}");
+          }
+        },
+        Err(RecvTimeoutError::Timeout) => {},
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
 
-    for (board_id, command) in commands {
-      // send sam control message to SAM
-      let message = match postcard::to_slice(&command, &mut buffer) {
-        Ok(package) =>  package,
-        Err(e) => {
-          fail!("postcard returned this error when attempting to serialize control message {command:#?} Aborting..: {e}");
-          handler::abort(&shared);
-          return;
+      for (board_id, queue) in queues.iter_mut() {
+        while let Some(queued) = queue.commands.pop_front() {
+          let command = &queued.command;
+
+          if queued.queued_at.elapsed() > COMMAND_QUEUE_EXPIRY {
+            fail!("Dropping command to {board_id} which expired before it could be sent: {command:?}");
+            queue.metrics.dropped += 1;
+            continue;
+          }
+
+          let attempts = attempt_number(&pending, board_id, command);
+
+          if attempts > COMMAND_MAX_ATTEMPTS {
+            warn!("Dropping command to {board_id} which has already been attempted {COMMAND_MAX_ATTEMPTS} times: {command:?}");
+            queue.metrics.dropped += 1;
+            continue;
+          }
+
+          match send(&shared, &sender, &sockets, &keyring, &mut buffer, board_id, command) {
+            Delivery::Sent => {
+              queue.metrics.sent += 1;
+
+              // nobody may be waiting to hear when it was sent
+              let _ = queued.sent.send(Instant::now());
+
+              if let SamControlMessage::ActuateValve { channel, powered } = *command {
+                pending.insert((board_id.clone(), channel), PendingCommand { powered, attempts, sent_at: Instant::now() });
+              }
+            },
+            Delivery::Unreachable => {
+              // put the command back and stop, so that this board's commands stay in order
+              queue.commands.push_front(queued);
+              break;
+            },
+            Delivery::Invalid => queue.metrics.dropped += 1,
+          }
         }
-      };
-      
-      let sockets = sockets.read().unwrap();
-      if let Some(socket) = sockets.get(&board_id) {
-        let socket = (socket.ip(), SAM_PORT);
-
-        match sender.send_to(message, socket) {
-          Ok(_) => {
-            match command {
-              SamControlMessage::ActuateValve { channel, powered } => {
-                pass!("The command was sent successfully: {} {board_id}'s channel {channel} valve.", if powered { "Power" } else { "Unpower" });
-              },
-              SamControlMessage::SetLed { channel, on } => {
-                pass!("The command was sent successfully: Turn {} {board_id}'s channel {channel} LED.", if on { "on" } else { "off" });
-              },
-            }
-          },
-          Err(e) => fail!("Couldn't send control message to board {board_id} via socket {socket:#?}: {e}"),
-        };
-      } else {
-        fail!("Couldn't find socket with board ID {board_id} in sockets HashMap.");
       }
+
+      pending.retain(|(board_id, channel), command| {
+        match confirm(&shared, board_id, *channel, command.powered) {
+          Some(true) => {
+            pass!("Confirmed that {board_id}'s channel {channel} valve is {}.", if command.powered { "powered" } else { "unpowered" });
+            return false;
+          },
+          // nothing to confirm against, so there is no point in retransmitting
+          None => return false,
+          Some(false) => {},
+        }
+
+        if command.sent_at.elapsed() < COMMAND_CONFIRMATION_TIMEOUT {
+          return true;
+        }
+
+        if command.attempts >= COMMAND_MAX_ATTEMPTS {
+          fail!("{board_id}'s channel {channel} valve never confirmed the command after {} attempts.", command.attempts);
+          protocol::send_report(&shared.control_sockets, &FlightReport::CommandFailed { board_id: board_id.clone(), channel: *channel, powered: command.powered });
+          return false;
+        }
+
+        warn!("{board_id}'s channel {channel} valve has not confirmed the command. Retransmitting.");
+        let message = SamControlMessage::ActuateValve { channel: *channel, powered: command.powered };
+        command.attempts += 1;
+        command.sent_at = Instant::now();
+
+        // an unsuccessful retransmission still counts as an attempt
+        !matches!(send(&shared, &sender, &sockets, &keyring, &mut buffer, board_id, &message), Delivery::Invalid)
+      });
+
+      *shared.command_queues.lock().unwrap() = queues
+        .iter()
+        .map(|(board_id, queue)| (board_id.clone(), CommandQueueMetrics { depth: queue.commands.len(), ..queue.metrics }))
+        .collect();
     }
 
     fail!("The FC unexpectedly dropped the command channel. Aborting and committing suicide...");
-    handler::abort(&shared);
+    handler::abort(&shared, AbortCause::SwitchboardFailure("command channel closed".to_owned()));
+  }
+}
+
+/// Which attempt at a command sending it now would be.
+///
+/// A command resent while it is still pending, such as a mismatch retry from the worker,
+/// is another attempt at it, so that COMMAND_MAX_ATTEMPTS bounds both kinds of retry.
+fn attempt_number(pending: &HashMap<(BoardId, u32), PendingCommand>, board_id: &BoardId, command: &SamControlMessage) -> u32 {
+  match *command {
+    SamControlMessage::ActuateValve { channel, powered } => match pending.get(&(board_id.clone(), channel)) {
+      Some(existing) if existing.powered == powered => existing.attempts + 1,
+      _ => 1,
+    },
+    _ => 1,
+  }
+}
+
+/// Sends a single control message to a board.
+fn send(shared: &SharedState, sender: &UdpSocket, sockets: &RwLock<HashMap<BoardId, SocketAddr>>, keyring: &Mutex<Keyring>, buffer: &mut [u8], board_id: &BoardId, command: &SamControlMessage) -> Delivery {
+  // send sam control message to SAM
+  let message = match postcard::to_slice(command, buffer) {
+    Ok(package) =>  package,
+    Err(e) => {
+      fail!("postcard returned this error when attempting to serialize control message {command:#?} Aborting..: {e}");
+      handler::abort(shared, AbortCause::SerializationFailure(Some(board_id.clone())));
+      return Delivery::Invalid;
+    }
+  };
+
+  let sockets = sockets.read().unwrap();
+  let Some(socket) = sockets.get(board_id) else {
+    return Delivery::Unreachable;
+  };
+
+  let socket = (socket.ip(), SAM_PORT);
+
+  let Some(datagram) = keyring.lock().unwrap().seal(board_id, message) else {
+    fail!("Couldn't send control message to board {board_id} because it has no key. Dropping it.");
+    return Delivery::Invalid;
+  };
+
+  match sender.send_to(&datagram, socket) {
+    Ok(_) => {
+      match command {
+        SamControlMessage::ActuateValve { channel, powered } => {
+          pass!("The command was sent: {} {board_id}'s channel {channel} valve.", if *powered { "Power" } else { "Unpower" });
+        },
+        SamControlMessage::SetLed { channel, on } => {
+          pass!("The command was sent: Turn {} {board_id}'s channel {channel} LED.", if *on { "on" } else { "off" });
+        },
+      }
+
+      Delivery::Sent
+    },
+    Err(e) => {
+      fail!("Couldn't send control message to board {board_id} via socket {socket:#?}: {e}");
+      Delivery::Unreachable
+    },
+  }
+}
+
+/// Checks whether a valve's measured current agrees with whether it was commanded
+/// to be powered, returning `None` if there is no mapping or threshold to check against.
+fn confirm(shared: &SharedState, board_id: &BoardId, channel: u32, powered: bool) -> Option<bool> {
+  let vehicle_state = shared.vehicle_state.lock().unwrap();
+  let mappings = shared.mappings.lock().unwrap();
+
+  let mapping = mappings
+    .iter()
+    .find(|m| m.board_id == *board_id && m.channel == channel && matches!(m.sensor_type, SensorType::Valve))?;
+
+  let threshold = mapping.powered_threshold?;
+
+  let current = vehicle_state.sensor_readings
+    .get(&format!("{}_I", mapping.text_id))?
+    .value;
+
+  Some((current >= threshold) == powered)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  fn actuate(channel: u32, powered: bool) -> SamControlMessage {
+    SamControlMessage::ActuateValve { channel, powered }
+  }
+
+  fn queued(command: SamControlMessage, queued_at: Instant) -> QueuedCommand {
+    QueuedCommand { command, queued_at, sent: mpsc::channel().0 }
+  }
+
+  #[test]
+  fn resending_a_pending_command_is_another_attempt() {
+    let board_id = "sam-01".to_owned();
+    let mut pending = HashMap::new();
+
+    assert_eq!(attempt_number(&pending, &board_id, &actuate(1, true)), 1);
+
+    pending.insert((board_id.clone(), 1), PendingCommand { powered: true, attempts: 2, sent_at: Instant::now() });
+
+    assert_eq!(attempt_number(&pending, &board_id, &actuate(1, true)), 3);
+
+    // a different command, channel, or board starts over
+    assert_eq!(attempt_number(&pending, &board_id, &actuate(1, false)), 1);
+    assert_eq!(attempt_number(&pending, &board_id, &actuate(2, true)), 1);
+    assert_eq!(attempt_number(&pending, &"sam-02".to_owned(), &actuate(1, true)), 1);
+    assert_eq!(attempt_number(&pending, &board_id, &SamControlMessage::SetLed { channel: 1, on: true }), 1);
+  }
+
+  #[test]
+  fn full_queue_drops_oldest_command() {
+    let mut queue = CommandQueue::default();
+    let now = Instant::now();
+
+    for channel in 0..COMMAND_QUEUE_CAPACITY as u32 {
+      assert!(queue.push(queued(actuate(channel, true), now)).is_none());
+    }
+
+    let dropped = queue.push(queued(actuate(COMMAND_QUEUE_CAPACITY as u32, true), now));
+
+    assert!(matches!(dropped, Some(SamControlMessage::ActuateValve { channel: 0, .. })));
+    assert_eq!(queue.commands.len(), COMMAND_QUEUE_CAPACITY);
+    assert_eq!(queue.metrics.dropped, 1);
+    assert!(matches!(queue.commands.front(), Some(QueuedCommand { command: SamControlMessage::ActuateValve { channel: 1, .. }, .. })));
   }
-}
\ No newline at end of file
+}
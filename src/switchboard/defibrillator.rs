@@ -1,10 +1,11 @@
 use std::{collections::{HashMap, HashSet}, net::{SocketAddr, UdpSocket}, sync::{Arc, Mutex, RwLock}, thread};
 use common::comm::{BoardId, DataMessage};
 use jeflog::fail;
-use crate::{handler, state::SharedState, HEARTBEAT_PERIOD};
+use super::keyring::Keyring;
+use crate::{abort::AbortCause, handler, state::SharedState, HEARTBEAT_PERIOD};
 
 /// Wakes every HEARTBEAT_RATE to send heartbeats to all the connected Sam boards to ensure that the FC isn't disconnected.
-pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() -> () {
+pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, statuses: Arc<Mutex<HashSet<BoardId>>>, keyring: Arc<Mutex<Keyring>>) -> impl FnOnce() -> () {
   move || {
     let mut buf = vec![0; crate::HEARTBEAT_BUFFER_SIZE];
 
@@ -12,7 +13,7 @@ pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock
       Ok(package) => package,
       Err(e) => {
         fail!("postcard returned this error when attempting to serialize DataMessage::FlightHeartbeat: {e}");
-        handler::abort(&shared);
+        handler::abort(&shared, AbortCause::SerializationFailure(None));
         return;
       }
     };
@@ -22,21 +23,36 @@ pub fn defibrillator(shared: SharedState, sender: UdpSocket, sockets: Arc<RwLock
 
       let sockets = sockets.read().unwrap();
       let statuses = statuses.lock().unwrap();
-      let mut abort = false;
+      let mut keyring = keyring.lock().unwrap();
+      let mut failed = Vec::new();
       for (board_id, address) in sockets.iter() {
         if !statuses.contains(board_id) {
           continue;
         }
 
-        if let Err(e) = sender.send_to(heartbeat, address) {
+        // every heartbeat is sealed separately, since each board has its own key and counter
+        let Some(datagram) = keyring.seal(board_id, heartbeat) else {
+          fail!("Couldn't send heartbeat to {board_id} because it has no key.");
+          failed.push(board_id.clone());
+          continue;
+        };
+
+        if let Err(e) = sender.send_to(&datagram, address) {
           fail!("Couldn't send heartbeat to address {address:#?}: {e}");
-          abort = true;
+          failed.push(board_id.clone());
         }
       }
 
-      if abort {
-        fail!("Aborting...");
-        handler::abort(&shared);
+      // catches the counters of boards which have gone quiet since they were last persisted
+      keyring.save_counters(false);
+
+      // release the locks before aborting, since aborting can take a while
+      drop(sockets);
+      drop(statuses);
+      drop(keyring);
+
+      for board_id in failed {
+        handler::abort(&shared, AbortCause::HeartbeatFailure(board_id));
       }
     }
   }
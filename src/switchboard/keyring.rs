@@ -0,0 +1,274 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path, sync::mpsc::{Receiver, Sender}, time::Instant};
+use common::comm::BoardId;
+use jeflog::{fail, pass, warn};
+use crate::{auth, persistence, BOARD_COUNTER_PERSIST_PERIOD};
+
+/// Mixed into every datagram's MAC so that a datagram cannot be reflected back to its sender.
+const BOARD_TO_FLIGHT: u8 = b'B';
+const FLIGHT_TO_BOARD: u8 = b'F';
+
+/// Why a datagram from a board was rejected.
+#[derive(Debug)]
+pub enum Rejection {
+  /// The datagram is too short to hold a counter and a MAC.
+  Malformed,
+
+  /// There is no key for the board the datagram claims to be from.
+  UnknownBoard,
+
+  /// The MAC does not match, so the datagram was not sent by the board.
+  Forged,
+
+  /// The counter is not greater than that of the last datagram accepted from
+  /// the board, so the datagram is being replayed.
+  Replayed,
+}
+
+impl fmt::Display for Rejection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Malformed => write!(f, "datagram is too short to be authenticated"),
+      Self::UnknownBoard => write!(f, "no key is known for the board"),
+      Self::Forged => write!(f, "MAC does not match"),
+      Self::Replayed => write!(f, "counter has already been used"),
+    }
+  }
+}
+
+/// Per-board keys and counters used to authenticate datagrams exchanged with boards.
+///
+/// Every datagram is sealed by `auth::seal_datagram` under the board's key.
+/// Boards must never reuse a counter, even across restarts, so the flight computer
+/// keeps its boot count in the upper half of the counters it sends. Likewise, the
+/// counters received from boards are persisted, so that datagrams captured before
+/// the flight computer restarts cannot be replayed after.
+#[derive(Debug)]
+pub struct Keyring {
+  keys: HashMap<BoardId, Vec<u8>>,
+  sent: HashMap<BoardId, u64>,
+  received: HashMap<BoardId, u64>,
+  boot_count: u32,
+
+  /// Whether `received` has changed since it was last persisted, and when that was.
+  unsaved: bool,
+  saved_at: Instant,
+
+  /// Where copies of `received` are sent to be persisted by `persist_counters`.
+  persister: Sender<HashMap<BoardId, u64>>,
+}
+
+impl Keyring {
+  /// Loads a key for every board from the `<board id>.key` files in the directory,
+  /// along with the persisted counters of the boards.
+  ///
+  /// A missing directory is treated as having no keys, so that the flight
+  /// computer still starts, though it cannot talk to any board.
+  pub fn load(directory: &Path, boot_count: u32, persister: Sender<HashMap<BoardId, u64>>) -> io::Result<Self> {
+    let mut keys = HashMap::new();
+
+    let entries = match fs::read_dir(directory) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        warn!("Board key directory {} does not exist. No boards can be authenticated.", directory.display());
+        return Ok(Keyring::new(keys, persistence::load_board_counters(), boot_count, persister));
+      },
+      Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+      let path = entry?.path();
+
+      if path.extension().is_some_and(|extension| extension == "key") {
+        let Some(board_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+          warn!("Ignoring board key with a non-UTF-8 name: {}", path.display());
+          continue;
+        };
+
+        keys.insert(board_id.to_owned(), fs::read(&path)?);
+      }
+    }
+
+    pass!("Loaded keys for {} boards from {}.", keys.len(), directory.display());
+
+    Ok(Keyring::new(keys, persistence::load_board_counters(), boot_count, persister))
+  }
+
+  fn new(keys: HashMap<BoardId, Vec<u8>>, received: HashMap<BoardId, u64>, boot_count: u32, persister: Sender<HashMap<BoardId, u64>>) -> Self {
+    Keyring {
+      keys,
+      sent: HashMap::new(),
+      received,
+      boot_count,
+      unsaved: false,
+      saved_at: Instant::now(),
+      persister,
+    }
+  }
+
+  /// Splits a datagram into its counter, message, and MAC.
+  pub fn split(datagram: &[u8]) -> Result<(u64, &[u8], &[u8]), Rejection> {
+    auth::split_datagram(datagram).ok_or(Rejection::Malformed)
+  }
+
+  /// Checks that a message split from a datagram was sent by the board and has
+  /// not been accepted before.
+  pub fn verify(&mut self, board_id: &BoardId, counter: u64, message: &[u8], tag: &[u8]) -> Result<(), Rejection> {
+    let key = self.keys.get(board_id).ok_or(Rejection::UnknownBoard)?;
+
+    if !auth::verify_datagram(key, BOARD_TO_FLIGHT, counter, message, tag) {
+      return Err(Rejection::Forged);
+    }
+
+    if self.received.get(board_id).is_some_and(|last| counter <= *last) {
+      return Err(Rejection::Replayed);
+    }
+
+    self.received.insert(board_id.clone(), counter);
+    self.unsaved = true;
+    Ok(())
+  }
+
+  /// Hands a copy of the counters received from boards to `persist_counters` if
+  /// they have changed, as long as `BOARD_COUNTER_PERSIST_PERIOD` has passed since
+  /// they were last handed over or `now` is set.
+  ///
+  /// Nothing is written here, since the keyring is locked by threads which must
+  /// keep up with heartbeats and data.
+  pub fn save_counters(&mut self, now: bool) {
+    if !self.unsaved || (!now && self.saved_at.elapsed() < BOARD_COUNTER_PERSIST_PERIOD) {
+      return;
+    }
+
+    if self.persister.send(self.received.clone()).is_err() {
+      fail!("Failed to persist board counters because the thread persisting them has stopped.");
+    }
+
+    self.unsaved = false;
+    self.saved_at = Instant::now();
+  }
+
+  /// Wraps a message in an authenticated datagram for the board, or returns
+  /// `None` if there is no key for the board.
+  pub fn seal(&mut self, board_id: &BoardId, message: &[u8]) -> Option<Vec<u8>> {
+    let key = self.keys.get(board_id)?;
+    let sent = self.sent.entry(board_id.clone()).or_insert(0);
+    let counter = ((self.boot_count as u64) << 32) | *sent;
+    *sent += 1;
+
+    Some(auth::seal_datagram(key, FLIGHT_TO_BOARD, counter, message))
+  }
+}
+
+/// Persists the counters received from boards as copies arrive from `Keyring::save_counters`,
+/// skipping to the latest copy if the disk falls behind.
+pub fn persist_counters(counters: Receiver<HashMap<BoardId, u64>>) -> impl FnOnce() -> () {
+  move || {
+    while let Ok(mut latest) = counters.recv() {
+      while let Ok(newer) = counters.try_recv() {
+        latest = newer;
+      }
+
+      if let Err(e) = persistence::save_board_counters(&latest) {
+        fail!("Failed to persist board counters: {e}");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  const KEY: &[u8] = b"board key";
+
+  fn keyring(received: HashMap<BoardId, u64>) -> (Keyring, Receiver<HashMap<BoardId, u64>>) {
+    let (persister, persisted) = mpsc::channel();
+    let keys = HashMap::from([("sam-01".to_owned(), KEY.to_vec())]);
+    (Keyring::new(keys, received, 3, persister), persisted)
+  }
+
+  /// Seals a message as the board would and verifies it.
+  fn receive(keyring: &mut Keyring, board_id: &str, counter: u64, message: &[u8]) -> Result<(), Rejection> {
+    let datagram = auth::seal_datagram(KEY, BOARD_TO_FLIGHT, counter, message);
+    let (counter, message, tag) = Keyring::split(&datagram)?;
+    keyring.verify(&board_id.to_owned(), counter, message, tag)
+  }
+
+  #[test]
+  fn counters_must_increase() {
+    let (mut keyring, _) = keyring(HashMap::new());
+
+    assert!(receive(&mut keyring, "sam-01", 0, b"data").is_ok());
+    assert!(receive(&mut keyring, "sam-01", 5, b"data").is_ok());
+    assert!(matches!(receive(&mut keyring, "sam-01", 5, b"data"), Err(Rejection::Replayed)));
+    assert!(matches!(receive(&mut keyring, "sam-01", 4, b"data"), Err(Rejection::Replayed)));
+    assert!(receive(&mut keyring, "sam-01", 6, b"data").is_ok());
+  }
+
+  #[test]
+  fn persisted_counters_reject_replays_after_restart() {
+    let (mut keyring, _) = keyring(HashMap::from([("sam-01".to_owned(), 10)]));
+
+    assert!(matches!(receive(&mut keyring, "sam-01", 10, b"data"), Err(Rejection::Replayed)));
+    assert!(receive(&mut keyring, "sam-01", 11, b"data").is_ok());
+  }
+
+  #[test]
+  fn forged_and_unknown_datagrams_are_rejected() {
+    let (mut keyring, _) = keyring(HashMap::new());
+
+    let mut datagram = auth::seal_datagram(KEY, BOARD_TO_FLIGHT, 1, b"data");
+    // flips a bit of the message, which follows the 8 byte counter
+    datagram[8] ^= 1;
+    let (counter, message, tag) = Keyring::split(&datagram).unwrap();
+    assert!(matches!(keyring.verify(&"sam-01".to_owned(), counter, message, tag), Err(Rejection::Forged)));
+
+    // the flight computer's own datagrams cannot be reflected back to it
+    let reflected = auth::seal_datagram(KEY, FLIGHT_TO_BOARD, 1, b"data");
+    let (counter, message, tag) = Keyring::split(&reflected).unwrap();
+    assert!(matches!(keyring.verify(&"sam-01".to_owned(), counter, message, tag), Err(Rejection::Forged)));
+
+    assert!(matches!(receive(&mut keyring, "sam-02", 1, b"data"), Err(Rejection::UnknownBoard)));
+    assert!(matches!(Keyring::split(&[0; 8]), Err(Rejection::Malformed)));
+
+    // a rejected datagram does not use up its counter
+    assert!(receive(&mut keyring, "sam-01", 1, b"data").is_ok());
+  }
+
+  #[test]
+  fn sealed_counters_carry_the_boot_count() {
+    let (mut keyring, _) = keyring(HashMap::new());
+
+    for expected in 0..2 {
+      let datagram = keyring.seal(&"sam-01".to_owned(), b"command").unwrap();
+      let (counter, message, tag) = auth::split_datagram(&datagram).unwrap();
+
+      assert_eq!(counter, (3 << 32) | expected);
+      assert_eq!(message, b"command");
+      assert!(auth::verify_datagram(KEY, FLIGHT_TO_BOARD, counter, message, tag));
+    }
+
+    assert!(keyring.seal(&"sam-02".to_owned(), b"command").is_none());
+  }
+
+  #[test]
+  fn changed_counters_are_handed_over_to_be_persisted() {
+    let (mut keyring, persisted) = keyring(HashMap::new());
+
+    keyring.save_counters(true);
+    assert!(persisted.try_recv().is_err());
+
+    receive(&mut keyring, "sam-01", 7, b"data").unwrap();
+
+    // not yet due
+    keyring.save_counters(false);
+    assert!(persisted.try_recv().is_err());
+
+    keyring.save_counters(true);
+    assert_eq!(persisted.try_recv().unwrap(), HashMap::from([("sam-01".to_owned(), 7)]));
+
+    keyring.save_counters(true);
+    assert!(persisted.try_recv().is_err());
+  }
+}
@@ -1,7 +1,7 @@
 use std::{collections::{HashMap, HashSet}, sync::{mpsc::{Receiver, TryRecvError}, Arc, Mutex}, time::Instant};
 use common::comm::BoardId;
 use jeflog::fail;
-use crate::{handler, state::SharedState, REFRESH_COUNT, TIME_TIL_DEATH};
+use crate::{abort::AbortCause, event::{self, Event}, handler, state::SharedState, REFRESH_COUNT, TIME_TIL_DEATH};
 
 /// Tracks the state of each board, detected if boards lose communications.
 pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mutex<HashSet<BoardId>>>) -> impl FnOnce() -> () {  
@@ -25,13 +25,14 @@ pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mu
 
         if !statuses.contains(&board_id) {
           statuses.insert(board_id.clone());
+          event::notify(&shared, Event::BoardStatus { board_id: board_id.clone(), alive: true });
         }
 
         // refresh timer
         timers.insert(board_id, Instant::now());
       }
 
-      let mut abort = false;
+      let mut dead = Vec::new();
       for board_id in timers.keys() {
         if !statuses.contains(board_id) {
           continue;
@@ -39,7 +40,7 @@ pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mu
 
         if Instant::now() - *timers.get(board_id).unwrap() > TIME_TIL_DEATH {
           statuses.remove(board_id);
-          abort = true;
+          dead.push(board_id.clone());
 
           //if let Err(e) = tui_tx.send(TuiMessage::Status(board_id.clone(), false)) {
           //  fail!("Couldn't send message to TUI. tui_rx might've been dropped: {e}");
@@ -50,13 +51,13 @@ pub fn lifetime(shared: SharedState, snooze: Receiver<BoardId>, statuses: Arc<Mu
       }
 
       drop(statuses);
-      if abort {
-        fail!("Aborting...");
-        handler::abort(&shared);
+      for board_id in dead {
+        event::notify(&shared, Event::BoardStatus { board_id: board_id.clone(), alive: false });
+        handler::abort(&shared, AbortCause::LossOfComms(board_id));
       }
     }
 
     fail!("Switchboard unexpectedly dropped the snooze channel. Aborting and committing suicide...");
-    handler::abort(&shared);
+    handler::abort(&shared, AbortCause::SwitchboardFailure("snooze channel closed".to_owned()));
   }
 }
\ No newline at end of file
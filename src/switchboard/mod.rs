@@ -3,19 +3,22 @@ mod worker;
 mod lifetime;
 mod defibrillator;
 mod commander;
+mod keyring;
 
 use switchboard::switchboard;
 use lifetime::lifetime;
 use worker::worker;
 use defibrillator::defibrillator;
 use commander::commander;
-use std::{collections::{HashMap, HashSet}, io, net::UdpSocket, sync::{mpsc, Arc, Mutex, RwLock}, thread};
-use crate::{state::SharedState, CommandSender};
+pub use commander::CommandQueueMetrics;
+use keyring::{persist_counters, Keyring};
+use std::{collections::{HashMap, HashSet}, io, net::UdpSocket, path::Path, sync::{mpsc, Arc, Mutex, RwLock}, thread};
+use crate::{state::SharedState, CommandSender, BOARD_KEY_DIRECTORY};
 
 // Concerns: might be a bit too abort happy?
 
 /// one-shot function that starts the switchboard.
-pub fn start(shared: SharedState, socket: UdpSocket) -> io::Result<CommandSender> {
+pub fn start(shared: SharedState, socket: UdpSocket, boot_count: u32) -> io::Result<CommandSender> {
   let reciever = socket.try_clone()?;
   let sender = socket.try_clone()?;
   let command_sender = socket.try_clone()?;
@@ -26,12 +29,27 @@ pub fn start(shared: SharedState, socket: UdpSocket) -> io::Result<CommandSender
 
   let statuses = Arc::new(Mutex::new(HashSet::new()));
   let sockets = Arc::new(RwLock::new(HashMap::new()));
+
+  let (counters_tx, counters_rx) = mpsc::channel();
+
+  let keyring = Keyring::load(Path::new(BOARD_KEY_DIRECTORY), boot_count, counters_tx)?;
+  let keyring = Arc::new(Mutex::new(keyring));
   
-  thread::spawn(switchboard(shared.clone(), snooze_tx, gig_tx, socket, reciever, sockets.clone()));
-  thread::spawn(lifetime(shared.clone(), snooze_rx, statuses.clone()));
-  thread::spawn(defibrillator(shared.clone(), sender, sockets.clone(), statuses.clone()));
-  thread::spawn(worker(shared.clone(), gig_rx));
-  thread::spawn(commander(shared.clone(), command_rx, command_sender, sockets.clone()));
+  // threads are named so that abort causes can be attributed to them
+  spawn("switchboard", switchboard(shared.clone(), snooze_tx, gig_tx, socket, reciever, sockets.clone(), keyring.clone()))?;
+  spawn("lifetime", lifetime(shared.clone(), snooze_rx, statuses.clone()))?;
+  spawn("defibrillator", defibrillator(shared.clone(), sender, sockets.clone(), statuses.clone(), keyring.clone()))?;
+  spawn("worker", worker(shared.clone(), gig_rx, command_tx.clone()))?;
+  spawn("commander", commander(shared.clone(), command_rx, command_sender, sockets.clone(), keyring))?;
+  spawn("counters", persist_counters(counters_rx))?;
 
   Ok(command_tx)
+}
+
+/// Spawns a named switchboard thread.
+fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+  thread::Builder::new()
+    .name(name.to_owned())
+    .spawn(f)
+    .map(|_| ())
 }
\ No newline at end of file
@@ -1,26 +1,49 @@
-use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, sync::{mpsc::Sender, Arc, RwLock}};
+use std::{collections::{HashMap, HashSet}, net::{SocketAddr, UdpSocket}, sync::{mpsc::Sender, Arc, Mutex, RwLock}, time::{Instant, SystemTime, UNIX_EPOCH}};
 use common::comm::{BoardId, DataMessage, DataPoint};
 use jeflog::{fail, pass, warn};
-use crate::{handler, state::SharedState, FC_BOARD_ID};
+use super::keyring::Keyring;
+use crate::{abort::AbortCause, handler, protocol::{self, FlightReport}, role, state::SharedState, TIME_TIL_DEATH};
 
 /// Wakes when there's something to be passed along. Think of it like a telephone operator.
-pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(BoardId, Vec<DataPoint>)>, handshake_sender: UdpSocket, reciever: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>) -> impl FnOnce() -> () {
+pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(BoardId, Vec<DataPoint>, f64)>, handshake_sender: UdpSocket, reciever: UdpSocket, sockets: Arc<RwLock<HashMap<BoardId, SocketAddr>>>, keyring: Arc<Mutex<Keyring>>) -> impl FnOnce() -> () {
   move || {
     let mut buffer = [0; crate::DATA_MESSAGE_BUFFER_SIZE];
 
+    // when each board was last heard from, to tell whether it is still alive
+    let mut last_heard = HashMap::<BoardId, Instant>::new();
+
+    // boards owned by the other computer, which are only warned about once
+    let role = shared.settings.lock().unwrap().role.clone();
+    let mut foreign = HashSet::new();
+
     loop {
       // Move the incoming UDP data into a buffer
       let (message_length, sender_address) = match reciever.recv_from(&mut buffer) {
         Ok(data) => data,
         Err(e) => {
           fail!("Couldn't insert data into switchboard buffer, aborting..: {e}");
-          handler::abort(&shared);
+          handler::abort(&shared, AbortCause::SwitchboardFailure(format!("failed to receive data: {e}")));
+          continue;
+        }
+      };
+
+      // in the same clock as the timestamps sent to the server, so that the worker can
+      // convert the board's timestamps to it
+      let received = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or(0.0);
+
+      let (counter, message, tag) = match Keyring::split(&buffer[..message_length]) {
+        Ok(parts) => parts,
+        Err(e) => {
+          warn!("Rejected datagram from {sender_address}: {e}");
           continue;
         }
       };
 
       // Interpret the data in the buffer
-      let incoming_data = match postcard::from_bytes::<DataMessage>(&buffer[..message_length]) {
+      let incoming_data = match postcard::from_bytes::<DataMessage>(message) {
         Ok(data) => data,
         Err(e) => {
           fail!("postcard couldn't interpret the buffer data, ignoring...: {e}");
@@ -28,16 +51,67 @@ pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(Bo
         }
       };
 
+      // the message is only trusted to say which board's key to check it against
+      let claimed_board_id = match &incoming_data {
+        DataMessage::Identity(board_id) | DataMessage::Sam(board_id, _) | DataMessage::Bms(board_id) => board_id,
+        DataMessage::FlightHeartbeat => {
+          warn!("Recieved a FlightHeartbeat from {sender_address}. This shouldn't happen, ignoring...");
+          continue;
+        }
+      };
+
+      let mut keys = keyring.lock().unwrap();
+
+      if let Err(e) = keys.verify(claimed_board_id, counter, message, tag) {
+        warn!("Rejected message claiming to be from {claimed_board_id} at {sender_address}: {e}");
+        continue;
+      }
+
+      // a replayed identity could redirect commands, so its counter is persisted right away
+      // rather than with the next batch
+      keys.save_counters(matches!(incoming_data, DataMessage::Identity(_)));
+      drop(keys);
+
+      if !role.owns(claimed_board_id) {
+        if foreign.insert(claimed_board_id.clone()) {
+          warn!("Ignoring {claimed_board_id} at {sender_address} because it is not owned by the {} computer.", role::name(&role.computer));
+        }
+
+        continue;
+      }
+
       let board_id = match incoming_data {
         DataMessage::Identity(board_id) => {
           let mut sockets = sockets.write().unwrap();
+          let previous = sockets.get(&board_id).copied();
+
+          // a board which is still alive should never change address, so the
+          // new address is not trusted until the old one stops responding
+          if let Some(previous) = previous.filter(|previous| *previous != sender_address) {
+            let alive = last_heard
+              .get(&board_id)
+              .is_some_and(|heard| heard.elapsed() <= TIME_TIL_DEATH);
+
+            if alive {
+              drop(sockets);
+              fail!("Recieved identity message from {board_id} at {sender_address}, but it is still alive at {previous}. Keeping the old address.");
+
+              let conflict = FlightReport::BoardAddressConflict { board_id, known: previous, new: sender_address };
+              protocol::send_report(&shared.control_sockets, &conflict);
+              continue;
+            }
+
+            warn!("Board {board_id} has moved from {previous} to {sender_address}.");
+          }
+
           sockets.insert(board_id.clone(), sender_address);
+          drop(sockets);
 
           pass!("Recieved identity message from board {board_id}");
 					
-					let identity = DataMessage::Identity(String::from(FC_BOARD_ID));
+					let identity = DataMessage::Identity(String::from(role.board_id()));
 
-					let handshake = match postcard::to_slice(&identity, &mut buffer) {
+					let handshake = match postcard::to_allocvec(&identity) {
 						Ok(identity) => identity,
 						Err(e) => {
 							warn!("postcard returned this error when attempting to serialize DataMessage::Identity: {e}");
@@ -45,7 +119,12 @@ pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(Bo
 						}
 					};
 
-					if let Err(e) = handshake_sender.send_to(handshake, sender_address) {
+					let Some(handshake) = keyring.lock().unwrap().seal(&board_id, &handshake) else {
+						warn!("Couldn't send DataMessage::Identity to {board_id} because it has no key.");
+						continue;
+					};
+
+					if let Err(e) = handshake_sender.send_to(&handshake, sender_address) {
 						fail!("Couldn't send DataMessage::Identity to ip {sender_address}: {e}");
 					} else {
 						pass!("Sent DataMessage::Identity to {sender_address} successfully.");
@@ -58,24 +137,24 @@ pub fn switchboard(shared: SharedState, snooze: Sender<BoardId>, gig: Sender<(Bo
           board_id
         },
         DataMessage::Sam(board_id, datapoints) => {
-          if let Err(e) = gig.send((board_id.clone(), datapoints.to_vec())) {
+          if let Err(e) = gig.send((board_id.clone(), datapoints.to_vec(), received)) {
             fail!("Worker unexpectedly dropped the receiving end of the gig channel ({e}). Aborting and committing suicide...");
-            handler::abort(&shared);
+            handler::abort(&shared, AbortCause::SwitchboardFailure("gig channel closed".to_owned()));
             break;
           }
 
           board_id
         },
         DataMessage::Bms(board_id) => board_id,
-        DataMessage::FlightHeartbeat => {
-          warn!("Recieved a FlightHeartbeat from {sender_address}. This shouldn't happen, ignoring...");
-          continue;
-        }
+        // rejected before authentication
+        DataMessage::FlightHeartbeat => continue,
       };
 
+      last_heard.insert(board_id.clone(), Instant::now());
+
       if let Err(e) = snooze.send(board_id) {
         fail!("Lifetime unexpectedly dropped the receiving end of the snooze channel ({e}). Aborting and committing suicide...");
-        handler::abort(&shared);
+        handler::abort(&shared, AbortCause::SwitchboardFailure("snooze channel closed".to_owned()));
         break;
       }
     }
@@ -1,24 +1,86 @@
-use std::sync::{mpsc::Receiver, Arc, Mutex};
-use common::comm::{BoardId, ChannelType, CompositeValveState, DataPoint, Measurement, NodeMapping, SensorType, Unit, ValveState, VehicleState};
-use jeflog::{fail, warn};
-use crate::{handler, state::SharedState};
+use std::{collections::HashMap, sync::mpsc::Receiver, time::Instant};
+use common::comm::{BoardId, ChannelType, CompositeValveState, DataPoint, Measurement, SensorType, Unit, ValveState};
+use jeflog::{fail, pass, warn};
+use crate::{abort::AbortCause, handler, protocol::{self, FlightReport}, state::SharedState, valve::{MismatchAction, MismatchEvent}, CommandSender};
+
+/// A change in a valve's mismatch status, to be handled once the worker has released its locks.
+struct Mismatch {
+  text_id: String,
+  commanded: ValveState,
+  actual: ValveState,
+  event: MismatchEvent,
+  action: MismatchAction,
+}
 
 /// deals with all the data processing, only wakes when there's data to be processed.
-pub fn worker(shared: SharedState, gig: Receiver<(BoardId, Vec<DataPoint>)>) -> impl FnOnce() -> () {
+pub fn worker(shared: SharedState, gig: Receiver<(BoardId, Vec<DataPoint>, f64)>, command_tx: CommandSender) -> impl FnOnce() -> () {
   move || {
-    for (board_id, datapoints) in gig {
-      process_sam_data(shared.vehicle_state.clone(), shared.mappings.clone(), board_id, datapoints)
+    for (board_id, datapoints, received) in gig {
+      let mismatches = process_sam_data(&shared, board_id, datapoints, received);
+
+      for mismatch in mismatches {
+        handle_mismatch(&shared, &command_tx, mismatch);
+      }
     }
 
     fail!("Switchboard has unexpectedly closed the gig channel. Aborting and committing suicide...");
-    handler::abort(&shared);
+    handler::abort(&shared, AbortCause::SwitchboardFailure("gig channel closed".to_owned()));
+  }
+}
+
+/// Raises, retries, or clears a valve mismatch alarm.
+fn handle_mismatch(shared: &SharedState, command_tx: &CommandSender, mismatch: Mismatch) {
+  let Mismatch { text_id, commanded, actual, event, action } = mismatch;
+
+  // retries are commanded like any other sequence would, so they stop once the
+  // vehicle is disarmed or aborts rather than resending a stale command
+  let arm_state = shared.arming.lock().unwrap().state;
+  let may_retry = arm_state.permits_actuation(false, false) && !shared.abort.lock().unwrap().aborted;
+
+  match event {
+    MismatchEvent::Raised { retry } => {
+      fail!("Valve '{text_id}' was commanded {commanded} but is {actual}.");
+      shared.valve_mismatches.lock().unwrap().insert(text_id.clone());
+      protocol::send_report(&shared.control_sockets, &FlightReport::ValveMismatch { text_id: text_id.clone(), commanded, actual });
+
+      if action == MismatchAction::Abort {
+        handler::abort(shared, AbortCause::ValveMismatch(text_id));
+      } else if retry && !may_retry {
+        warn!("Not resending command to valve '{text_id}' because the vehicle is {arm_state}.");
+      } else if retry {
+        warn!("Resending command to valve '{text_id}'.");
+
+        // interlock violations are reported by actuate_valve itself
+        let _ = handler::actuate_valve(shared, &text_id, commanded, command_tx, false);
+      }
+    },
+    MismatchEvent::Retry if !may_retry => {
+      warn!("Valve '{text_id}' is still {actual}, but not resending command because the vehicle is {arm_state}.");
+    },
+    MismatchEvent::Retry => {
+      warn!("Valve '{text_id}' is still {actual}. Resending command.");
+      let _ = handler::actuate_valve(shared, &text_id, commanded, command_tx, false);
+    },
+    MismatchEvent::Cleared => {
+      pass!("Valve '{text_id}' reached its commanded state of {commanded}.");
+      shared.valve_mismatches.lock().unwrap().remove(&text_id);
+      protocol::send_report(&shared.control_sockets, &FlightReport::ValveMismatchCleared(text_id));
+    },
   }
 }
 
-fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex<Vec<NodeMapping>>>, board_id: BoardId, datapoints: Vec<DataPoint>) {
-	let mut vehicle_state = vehicle_state.lock().unwrap();
+fn process_sam_data(shared: &SharedState, board_id: BoardId, datapoints: Vec<DataPoint>, received: f64) -> Vec<Mismatch> {
+	let mut vehicle_state = shared.vehicle_state.lock().unwrap();
 
-	let mappings = mappings.lock().unwrap();
+	let mappings = shared.mappings.lock().unwrap();
+	let mut tares = shared.tares.lock().unwrap();
+	let valve_thresholds = shared.valve_thresholds.lock().unwrap();
+	let mismatch_settings = shared.mismatch_settings.lock().unwrap();
+	let mut acquisition_times = shared.acquisition_times.lock().unwrap();
+	let mut valves = shared.valve_trackers.lock().unwrap();
+	let now = Instant::now();
+	let mut mismatches = Vec::new();
+	let offset = clock_offset(&datapoints, received);
 
 	for data_point in datapoints {
 		for mapping in &*mappings {
@@ -104,7 +166,39 @@ fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex
 						},
 					};
 
-					let actual_state = estimate_valve_state(voltage, current, mapping.powered_threshold, mapping.normally_closed);
+					let thresholds = valve_thresholds
+						.get(&mapping.text_id)
+						.copied()
+						.unwrap_or_default();
+
+					let commanded = vehicle_state.valve_states
+						.get(&mapping.text_id)
+						.map_or(ValveState::Undetermined, |state| state.commanded);
+
+					let actual_state = valves.estimators
+						.entry(mapping.text_id.clone())
+						.or_default()
+						.update(voltage, current, mapping, &thresholds, commanded, now);
+
+					let settings = mismatch_settings
+						.get(&mapping.text_id)
+						.copied()
+						.unwrap_or_default();
+
+					let event = valves.monitors
+						.entry(mapping.text_id.clone())
+						.or_default()
+						.update(actual_state, commanded, &settings, now);
+
+					if let Some(event) = event {
+						mismatches.push(Mismatch {
+							text_id: mapping.text_id.clone(),
+							commanded,
+							actual: actual_state,
+							event,
+							action: settings.action,
+						});
+					}
 
 					if let Some(existing) = vehicle_state.valve_states.get_mut(&mapping.text_id) {
 						existing.actual = actual_state;
@@ -115,10 +209,18 @@ fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex
 						});
 					}
 
+					record_acquisition(&mut acquisition_times, &mapping.text_id, data_point.timestamp + offset);
+
 					measurement
 				},
 			};
 
+			if let Some(accumulator) = tares.get_mut(&text_id) {
+				accumulator.add(measurement.value);
+			}
+
+			record_acquisition(&mut acquisition_times, &text_id, data_point.timestamp + offset);
+
 			// replace item without cloning string if already present
 			if let Some(existing) = vehicle_state.sensor_readings.get_mut(&text_id) {
 				*existing = measurement;
@@ -127,37 +229,55 @@ fn process_sam_data(vehicle_state: Arc<Mutex<VehicleState>>, mappings: Arc<Mutex
 			}
 		}
 	}
+
+	mismatches
 }
 
-/// Estimates the state of a valve given its voltage, current, and the current threshold at which it is considered powered.
-fn estimate_valve_state(voltage: f64, current: f64, powered_threshold: Option<f64>, normally_closed: Option<bool>) -> ValveState {
-	// calculate the actual state of the valve, assuming that it's normally closed
-	let mut estimated = match powered_threshold {
-		Some(powered) => {
-			if current < powered { // valve is unpowered
-				if voltage < 4.0 {
-					ValveState::Closed
-				} else {
-					ValveState::Disconnected
-				}
-			 } else { // valve is powered
-				if voltage < 20.0 {
-					ValveState::Fault
-				} else {
-					ValveState::Open
-				}
-			}
-		},
-		None => ValveState::Fault,
-	};
-
-	if normally_closed == Some(false) {
-		estimated = match estimated {
-			ValveState::Open => ValveState::Closed,
-			ValveState::Closed => ValveState::Open,
-			other => other,
-		};
+/// How far ahead the flight computer's clock is of the board's, given when the
+/// flight computer received the board's data points, in seconds since the UNIX epoch.
+///
+/// Boards timestamp data points with their own clocks, which are not synchronized
+/// with the flight computer's. The newest data point is taken to have been
+/// acquired as it was received, so converted times are late by at most the time
+/// the data took to reach the flight computer.
+fn clock_offset(datapoints: &[DataPoint], received: f64) -> f64 {
+	let newest = datapoints
+		.iter()
+		.map(|data_point| data_point.timestamp)
+		.fold(f64::NEG_INFINITY, f64::max);
+
+	if newest.is_finite() { received - newest } else { 0.0 }
+}
+
+/// Records when a reading was acquired, in the flight computer's clock as seconds
+/// since the UNIX epoch, without cloning the name if already present.
+fn record_acquisition(acquisition_times: &mut HashMap<String, f64>, text_id: &str, timestamp: f64) {
+	if let Some(existing) = acquisition_times.get_mut(text_id) {
+		*existing = timestamp;
+	} else {
+		acquisition_times.insert(text_id.to_owned(), timestamp);
 	}
+}
 
-	estimated
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn data_point(timestamp: f64) -> DataPoint {
+    DataPoint { value: 0.0, timestamp, channel: 1, channel_type: ChannelType::CurrentLoop }
+  }
+
+  #[test]
+  fn newest_data_point_is_taken_as_received() {
+    let datapoints = [data_point(10.0), data_point(12.5), data_point(11.0)];
+    let offset = clock_offset(&datapoints, 1_000.0);
+
+    assert_eq!(offset, 987.5);
+    assert_eq!(datapoints[0].timestamp + offset, 997.5);
+  }
+
+  #[test]
+  fn empty_batch_has_no_offset() {
+    assert_eq!(clock_offset(&[], 1_000.0), 0.0);
+  }
+}
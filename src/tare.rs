@@ -0,0 +1,131 @@
+use common::comm::SensorType;
+use jeflog::{fail, pass, warn};
+use serde::{Deserialize, Serialize};
+use std::{thread, time::Duration};
+use crate::{persistence, protocol::{self, FlightReport}, state::SharedState};
+
+/// Which mappings a tare applies to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum TareTarget {
+	/// A single mapping, by `text_id`.
+	Mapping(String),
+
+	/// Every mapping of a sensor type. Only PTs and load cells can be tared.
+	SensorType(SensorType),
+}
+
+/// Running sum of the readings of a mapping being tared, added to by the worker.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TareAccumulator {
+	pub sum: f64,
+	pub samples: u32,
+}
+
+impl TareAccumulator {
+	pub fn add(&mut self, value: f64) {
+		self.sum += value;
+		self.samples += 1;
+	}
+}
+
+/// Averages the readings of the targeted mappings over the window and adjusts
+/// their calibrated offsets so that they read zero, reporting the new offsets
+/// to the server.
+///
+/// Blocks for the duration of the window, so this should be run on its own thread.
+pub fn tare(shared: SharedState, target: TareTarget, window: Duration) {
+	let text_ids = shared.mappings
+		.lock()
+		.unwrap()
+		.iter()
+		.filter(|mapping| match &target {
+			TareTarget::Mapping(text_id) => mapping.text_id == *text_id,
+			TareTarget::SensorType(sensor_type) => mapping.sensor_type == *sensor_type,
+		})
+		.filter(|mapping| {
+			// offsets are only applied to PTs and load cells with both ratings set
+			let tareable = matches!(mapping.sensor_type, SensorType::Pt | SensorType::LoadCell)
+				&& mapping.max.is_some()
+				&& mapping.min.is_some();
+
+			if !tareable {
+				warn!("Cannot tare '{}' because it is not a PT or load cell with a max and min set.", mapping.text_id);
+			}
+
+			tareable
+		})
+		.map(|mapping| mapping.text_id.clone())
+		.collect::<Vec<_>>();
+
+	if text_ids.is_empty() {
+		fail!("No mappings matched tare target {target:?}.");
+		return;
+	}
+
+	let mut tares = shared.tares.lock().unwrap();
+
+	// the accumulators belong to whichever tare started them, so overlapping tares
+	// are rejected rather than taking over another tare's accumulator
+	let text_ids = text_ids
+		.into_iter()
+		.filter(|text_id| {
+			if tares.contains_key(text_id) {
+				warn!("'{text_id}' is already being tared. Skipping it.");
+				return false;
+			}
+
+			tares.insert(text_id.clone(), TareAccumulator::default());
+			true
+		})
+		.collect::<Vec<_>>();
+
+	drop(tares);
+
+	if text_ids.is_empty() {
+		fail!("Every mapping matched by tare target {target:?} is already being tared.");
+		return;
+	}
+
+	pass!("Taring {text_ids:?} over {window:?}.");
+	thread::sleep(window);
+
+	let mut tares = shared.tares.lock().unwrap();
+
+	let averages = text_ids
+		.iter()
+		.filter_map(|text_id| {
+			let accumulator = tares.remove(text_id)?;
+
+			if accumulator.samples == 0 {
+				fail!("No readings were received for '{text_id}' while taring. Leaving its offset unchanged.");
+				return None;
+			}
+
+			Some((text_id, accumulator.sum / accumulator.samples as f64))
+		})
+		.collect::<Vec<_>>();
+
+	drop(tares);
+
+	let mut mappings = shared.mappings.lock().unwrap();
+	let mut offsets = Vec::new();
+
+	for (text_id, average) in averages {
+		let Some(mapping) = mappings.iter_mut().find(|m| m.text_id == *text_id) else {
+			warn!("Mapping '{text_id}' was removed while being tared.");
+			continue;
+		};
+
+		// readings have the offset subtracted, so reading zero requires adding the average to it
+		mapping.calibrated_offset += average;
+		offsets.push((text_id.clone(), mapping.calibrated_offset));
+		pass!("Tared '{text_id}' with a calibrated offset of {}.", mapping.calibrated_offset);
+	}
+
+	shared.config_versions.lock().unwrap().mappings.update(&*mappings);
+
+	drop(mappings);
+	persistence::save(&shared);
+
+	protocol::send_report(&shared.control_sockets, &FlightReport::TareOffsets(offsets));
+}
@@ -0,0 +1,162 @@
+use common::comm::{NodeMapping, SensorType};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt};
+
+/// How serious a problem found in a mapping set is.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Severity {
+	/// The mapping will work, but probably not as intended.
+	Warning,
+
+	/// The mapping cannot work as written.
+	Error,
+}
+
+/// A single problem found in a mapping set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MappingIssue {
+	pub severity: Severity,
+
+	/// The `text_id` of the offending mapping.
+	pub text_id: String,
+
+	/// Human-readable description of the problem.
+	pub message: String,
+}
+
+impl fmt::Display for MappingIssue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:?} in mapping '{}': {}", self.severity, self.text_id, self.message)
+	}
+}
+
+/// Checks a mapping set for problems which would otherwise go unnoticed until a test.
+pub fn validate_mappings(mappings: &[NodeMapping]) -> Vec<MappingIssue> {
+	let mut issues = Vec::new();
+	let mut text_ids = HashSet::new();
+
+	let mut issue = |severity, mapping: &NodeMapping, message: String| {
+		issues.push(MappingIssue { severity, text_id: mapping.text_id.clone(), message });
+	};
+
+	for (index, mapping) in mappings.iter().enumerate() {
+		if mapping.text_id.is_empty() {
+			issue(Severity::Error, mapping, "text ID is empty".to_owned());
+		}
+
+		if !text_ids.insert(&mapping.text_id) {
+			issue(Severity::Error, mapping, "text ID is used by more than one mapping".to_owned());
+		}
+
+		// only compare against later mappings so each conflict is reported once
+		for other in &mappings[index + 1..] {
+			let shares_channel = other.board_id == mapping.board_id
+				&& other.channel == mapping.channel
+				&& mapping.sensor_type
+					.channel_types()
+					.iter()
+					.any(|channel_type| other.sensor_type.channel_types().contains(channel_type));
+
+			if shares_channel {
+				issue(Severity::Error, mapping, format!(
+					"channel {} on {} is also mapped to '{}'",
+					mapping.channel,
+					mapping.board_id,
+					other.text_id,
+				));
+			}
+		}
+
+		match mapping.sensor_type {
+			SensorType::Pt | SensorType::LoadCell => {
+				match (mapping.max, mapping.min) {
+					(Some(max), Some(min)) if max <= min => {
+						issue(Severity::Error, mapping, format!("max ({max}) is not greater than min ({min})"));
+					},
+					(Some(_), Some(_)) => {},
+					_ => {
+						issue(Severity::Warning, mapping, "max or min is missing, so readings will be reported in volts".to_owned());
+					},
+				}
+			},
+			SensorType::Valve => {
+				if mapping.powered_threshold.is_none() {
+					issue(Severity::Error, mapping, "powered threshold is missing, so the valve state will always be estimated as a fault".to_owned());
+				}
+
+				if mapping.normally_closed.is_none() {
+					issue(Severity::Warning, mapping, "normally closed is not specified, so the valve is assumed to be normally closed".to_owned());
+				}
+			},
+			_ => {},
+		}
+	}
+
+	issues
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::Computer;
+
+	fn mapping(text_id: &str, sensor_type: SensorType, channel: u32) -> NodeMapping {
+		NodeMapping {
+			text_id: text_id.to_owned(),
+			board_id: "sam-01".to_owned(),
+			sensor_type,
+			channel,
+			computer: Computer::Flight,
+			max: None,
+			min: None,
+			calibrated_offset: 0.0,
+			powered_threshold: None,
+			normally_closed: None,
+		}
+	}
+
+	fn pt(text_id: &str, channel: u32) -> NodeMapping {
+		NodeMapping { max: Some(1000.0), min: Some(0.0), ..mapping(text_id, SensorType::Pt, channel) }
+	}
+
+	fn valve(text_id: &str, channel: u32) -> NodeMapping {
+		NodeMapping { powered_threshold: Some(0.1), normally_closed: Some(true), ..mapping(text_id, SensorType::Valve, channel) }
+	}
+
+	/// The severity and `text_id` of every issue, in order.
+	fn summarize(issues: &[MappingIssue]) -> Vec<(Severity, &str)> {
+		issues
+			.iter()
+			.map(|issue| (issue.severity, issue.text_id.as_str()))
+			.collect()
+	}
+
+	#[test]
+	fn complete_mappings_have_no_issues() {
+		assert!(validate_mappings(&[pt("pt_1", 1), valve("valve_1", 2)]).is_empty());
+	}
+
+	#[test]
+	fn text_ids_must_be_present_and_unique() {
+		let issues = validate_mappings(&[pt("", 1), pt("pt_1", 2), pt("pt_1", 3)]);
+		assert_eq!(summarize(&issues), [(Severity::Error, ""), (Severity::Error, "pt_1")]);
+	}
+
+	#[test]
+	fn ranges_are_checked() {
+		let inverted = NodeMapping { max: Some(0.0), min: Some(1000.0), ..pt("inverted", 1) };
+		let missing = NodeMapping { max: None, ..pt("missing", 2) };
+
+		let issues = validate_mappings(&[inverted, missing]);
+		assert_eq!(summarize(&issues), [(Severity::Error, "inverted"), (Severity::Warning, "missing")]);
+	}
+
+	#[test]
+	fn valves_need_a_powered_threshold() {
+		let no_threshold = NodeMapping { powered_threshold: None, ..valve("no_threshold", 1) };
+		let no_default = NodeMapping { normally_closed: None, ..valve("no_default", 2) };
+
+		let issues = validate_mappings(&[no_threshold, no_default]);
+		assert_eq!(summarize(&issues), [(Severity::Error, "no_threshold"), (Severity::Warning, "no_default")]);
+	}
+}
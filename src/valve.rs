@@ -0,0 +1,430 @@
+use common::comm::{NodeMapping, ValveState};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, mem, time::{Duration, Instant}};
+
+/// Thresholds used to estimate the state of a single valve from its voltage and current.
+///
+/// The current threshold at which a valve is considered powered is still taken
+/// from its mapping's `powered_threshold`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ValveThresholds {
+	/// Voltage below which an unpowered valve is considered closed rather than disconnected.
+	pub unpowered_voltage: f64,
+
+	/// Voltage at or above which a powered valve is considered open rather than faulted.
+	pub powered_voltage: f64,
+
+	/// How far past a voltage threshold a reading must go before it is considered
+	/// to have crossed it, so that noise around the threshold is ignored.
+	pub voltage_hysteresis: f64,
+
+	/// How far past the powered threshold a current reading must go before it is
+	/// considered to have crossed it.
+	pub current_hysteresis: f64,
+
+	/// How long a new estimate must persist before it is reported. Zero, the
+	/// default, reports every estimate as soon as it is made.
+	pub debounce: Duration,
+}
+
+impl Default for ValveThresholds {
+	fn default() -> Self {
+		ValveThresholds {
+			unpowered_voltage: 4.0,
+			powered_voltage: 20.0,
+			voltage_hysteresis: 0.0,
+			current_hysteresis: 0.0,
+			debounce: Duration::ZERO,
+		}
+	}
+}
+
+/// The estimators and mismatch monitors of every valve which has sent a reading,
+/// by `text_id`. Updated by the worker and pruned when mappings are removed.
+#[derive(Debug, Default)]
+pub struct ValveTrackers {
+	pub estimators: HashMap<String, ValveEstimator>,
+	pub monitors: HashMap<String, MismatchMonitor>,
+}
+
+/// Debounced estimate of the state of a single valve, updated with every reading.
+#[derive(Clone, Debug)]
+pub struct ValveEstimator {
+	powered: bool,
+	above_unpowered_voltage: bool,
+	above_powered_voltage: bool,
+
+	/// The last estimate which persisted for the debounce window.
+	stable: ValveState,
+
+	/// An estimate differing from the stable one, and when it was first seen.
+	pending: Option<(ValveState, Instant)>,
+}
+
+impl Default for ValveEstimator {
+	fn default() -> Self {
+		ValveEstimator {
+			powered: false,
+			above_unpowered_voltage: false,
+			above_powered_voltage: false,
+			stable: ValveState::Undetermined,
+			pending: None,
+		}
+	}
+}
+
+impl ValveEstimator {
+	/// Updates the estimate with a new voltage and current reading of the valve
+	/// with the given mapping, returning the state which should be reported.
+	///
+	/// While a valve is moving towards its commanded state but has not yet settled
+	/// there, it is reported as `Undetermined`.
+	pub fn update(
+		&mut self,
+		voltage: f64,
+		current: f64,
+		mapping: &NodeMapping,
+		thresholds: &ValveThresholds,
+		commanded: ValveState,
+		now: Instant,
+	) -> ValveState {
+		// calculate the actual state of the valve, assuming that it's normally closed
+		let mut estimate = match mapping.powered_threshold {
+			Some(powered_threshold) => {
+				self.powered = above(current, powered_threshold, thresholds.current_hysteresis, self.powered);
+				self.above_unpowered_voltage = above(voltage, thresholds.unpowered_voltage, thresholds.voltage_hysteresis, self.above_unpowered_voltage);
+				self.above_powered_voltage = above(voltage, thresholds.powered_voltage, thresholds.voltage_hysteresis, self.above_powered_voltage);
+
+				match (self.powered, self.above_unpowered_voltage, self.above_powered_voltage) {
+					(false, false, _) => ValveState::Closed,
+					(false, true, _) => ValveState::Disconnected,
+					(true, _, false) => ValveState::Fault,
+					(true, _, true) => ValveState::Open,
+				}
+			},
+			None => ValveState::Fault,
+		};
+
+		if mapping.normally_closed == Some(false) {
+			estimate = match estimate {
+				ValveState::Open => ValveState::Closed,
+				ValveState::Closed => ValveState::Open,
+				other => other,
+			};
+		}
+
+		if estimate == self.stable {
+			self.pending = None;
+			return self.stable;
+		}
+
+		let since = match self.pending {
+			Some((pending, since)) if pending == estimate => since,
+			_ => now,
+		};
+
+		if now.duration_since(since) >= thresholds.debounce {
+			self.stable = estimate;
+			self.pending = None;
+			return self.stable;
+		}
+
+		self.pending = Some((estimate, since));
+
+		if estimate == commanded {
+			ValveState::Undetermined
+		} else {
+			self.stable
+		}
+	}
+}
+
+/// Whether `value` is above `threshold`, given whether it was previously. The
+/// value must pass the threshold by `hysteresis` to change sides of it.
+fn above(value: f64, threshold: f64, hysteresis: f64, was_above: bool) -> bool {
+	if was_above {
+		value >= threshold - hysteresis
+	} else {
+		value >= threshold + hysteresis
+	}
+}
+
+/// What the flight computer does when a valve does not reach its commanded state in time.
+/// An alarm is raised in every case.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MismatchAction {
+	/// Only raise the alarm.
+	Alarm,
+
+	/// Resend the command up to the given number of times, once per response time.
+	Retry(u32),
+
+	/// Abort.
+	Abort,
+}
+
+/// How a valve's commanded and actual states are compared.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MismatchSettings {
+	/// How long the valve may take to reach its commanded state before it is
+	/// considered mismatched.
+	pub response_time: Duration,
+
+	pub action: MismatchAction,
+}
+
+impl Default for MismatchSettings {
+	fn default() -> Self {
+		MismatchSettings {
+			response_time: Duration::from_millis(500),
+			action: MismatchAction::Alarm,
+		}
+	}
+}
+
+/// A change in whether a valve is mismatched with its commanded state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MismatchEvent {
+	/// The valve has disagreed with its commanded state for longer than its
+	/// response time, and the command should be resent if `retry` is set.
+	Raised { retry: bool },
+
+	/// The valve is still mismatched after another response time and the command
+	/// should be resent.
+	Retry,
+
+	/// The valve has reached its commanded state after an alarm was raised.
+	Cleared,
+}
+
+/// Tracks how long a single valve's actual state has disagreed with its commanded state.
+#[derive(Clone, Debug)]
+pub struct MismatchMonitor {
+	commanded: ValveState,
+	since: Option<Instant>,
+	alarmed: bool,
+	retries: u32,
+}
+
+impl Default for MismatchMonitor {
+	fn default() -> Self {
+		MismatchMonitor {
+			commanded: ValveState::Undetermined,
+			since: None,
+			alarmed: false,
+			retries: 0,
+		}
+	}
+}
+
+impl MismatchMonitor {
+	/// Compares the valve's latest actual state against its commanded state,
+	/// returning an event if the valve's mismatch status changed.
+	pub fn update(&mut self, actual: ValveState, commanded: ValveState, settings: &MismatchSettings, now: Instant) -> Option<MismatchEvent> {
+		// a new command gets its own response time and retries
+		if commanded != self.commanded {
+			self.commanded = commanded;
+			self.since = None;
+			self.retries = 0;
+		}
+
+		if commanded == ValveState::Undetermined || actual == commanded {
+			self.since = None;
+			return mem::take(&mut self.alarmed).then_some(MismatchEvent::Cleared);
+		}
+
+		let since = *self.since.get_or_insert(now);
+
+		if now.duration_since(since) < settings.response_time {
+			return None;
+		}
+
+		// restart the window so that retries are spaced out by the response time
+		self.since = Some(now);
+
+		let retry = match settings.action {
+			MismatchAction::Retry(attempts) => self.retries < attempts,
+			_ => false,
+		};
+
+		if retry {
+			self.retries += 1;
+		}
+
+		if self.alarmed {
+			retry.then_some(MismatchEvent::Retry)
+		} else {
+			self.alarmed = true;
+			Some(MismatchEvent::Raised { retry })
+		}
+	}
+
+	/// Gives up retrying the current command, as when the flight computer aborts.
+	/// The next command the valve is given gets its own retries as usual.
+	pub fn cancel_retries(&mut self) {
+		self.retries = u32::MAX;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::comm::{Computer, SensorType};
+
+	fn mapping(normally_closed: bool) -> NodeMapping {
+		NodeMapping {
+			text_id: "valve".to_owned(),
+			board_id: "sam-01".to_owned(),
+			sensor_type: SensorType::Valve,
+			channel: 1,
+			computer: Computer::Flight,
+			max: None,
+			min: None,
+			calibrated_offset: 0.0,
+			powered_threshold: Some(0.5),
+			normally_closed: Some(normally_closed),
+		}
+	}
+
+	#[test]
+	fn current_hysteresis_ignores_noise_around_threshold() {
+		let mapping = mapping(true);
+		let thresholds = ValveThresholds { current_hysteresis: 0.05, ..Default::default() };
+		let mut estimator = ValveEstimator::default();
+		let now = Instant::now();
+
+		let mut update = |voltage, current| estimator.update(voltage, current, &mapping, &thresholds, ValveState::Open, now);
+
+		assert_eq!(update(0.0, 0.0), ValveState::Closed);
+
+		// above the threshold, but not by the hysteresis
+		assert_eq!(update(0.0, 0.52), ValveState::Closed);
+		assert_eq!(update(24.0, 0.6), ValveState::Open);
+
+		// below the threshold, but not by the hysteresis
+		assert_eq!(update(24.0, 0.48), ValveState::Open);
+		assert_eq!(update(0.0, 0.4), ValveState::Closed);
+	}
+
+	#[test]
+	fn voltage_hysteresis_ignores_noise_around_threshold() {
+		let mapping = mapping(true);
+		let thresholds = ValveThresholds { voltage_hysteresis: 1.0, ..Default::default() };
+		let mut estimator = ValveEstimator::default();
+		let now = Instant::now();
+
+		let mut update = |voltage| estimator.update(voltage, 1.0, &mapping, &thresholds, ValveState::Open, now);
+
+		assert_eq!(update(24.0), ValveState::Open);
+		assert_eq!(update(19.5), ValveState::Open);
+		assert_eq!(update(18.5), ValveState::Fault);
+		assert_eq!(update(20.5), ValveState::Fault);
+		assert_eq!(update(21.5), ValveState::Open);
+	}
+
+	#[test]
+	fn debounce_holds_estimate_until_it_persists() {
+		let mapping = mapping(true);
+		let thresholds = ValveThresholds { debounce: Duration::from_millis(100), ..Default::default() };
+		let mut estimator = ValveEstimator::default();
+		let start = Instant::now();
+
+		assert_eq!(estimator.update(0.0, 0.0, &mapping, &thresholds, ValveState::Closed, start + Duration::from_millis(100)), ValveState::Undetermined);
+		assert_eq!(estimator.update(0.0, 0.0, &mapping, &thresholds, ValveState::Closed, start + Duration::from_millis(200)), ValveState::Closed);
+
+		// moving towards the commanded state is reported as undetermined until it settles
+		assert_eq!(estimator.update(24.0, 1.0, &mapping, &thresholds, ValveState::Open, start + Duration::from_millis(250)), ValveState::Undetermined);
+		assert_eq!(estimator.update(24.0, 1.0, &mapping, &thresholds, ValveState::Open, start + Duration::from_millis(350)), ValveState::Open);
+
+		// a glitch away from the commanded state is not reported at all
+		assert_eq!(estimator.update(0.0, 0.0, &mapping, &thresholds, ValveState::Open, start + Duration::from_millis(400)), ValveState::Open);
+		assert_eq!(estimator.update(24.0, 1.0, &mapping, &thresholds, ValveState::Open, start + Duration::from_millis(450)), ValveState::Open);
+	}
+
+	#[test]
+	fn normally_open_valves_are_inverted() {
+		let mapping = mapping(false);
+		let thresholds = ValveThresholds::default();
+		let mut estimator = ValveEstimator::default();
+		let now = Instant::now();
+
+		assert_eq!(estimator.update(0.0, 0.0, &mapping, &thresholds, ValveState::Open, now), ValveState::Open);
+		assert_eq!(estimator.update(24.0, 1.0, &mapping, &thresholds, ValveState::Open, now), ValveState::Closed);
+	}
+
+	fn after(start: Instant, milliseconds: u64) -> Instant {
+		start + Duration::from_millis(milliseconds)
+	}
+
+	#[test]
+	fn mismatch_is_raised_after_response_time_and_cleared() {
+		let settings = MismatchSettings::default();
+		let mut monitor = MismatchMonitor::default();
+		let start = Instant::now();
+
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, start), None);
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 499)), None);
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 500)), Some(MismatchEvent::Raised { retry: false }));
+
+		// an alarm that only raises is not repeated
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 1_000)), None);
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Open, &settings, after(start, 1_100)), Some(MismatchEvent::Cleared));
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Open, &settings, after(start, 1_200)), None);
+	}
+
+	#[test]
+	fn reaching_commanded_state_in_time_raises_nothing() {
+		let settings = MismatchSettings::default();
+		let mut monitor = MismatchMonitor::default();
+		let start = Instant::now();
+
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, start), None);
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Open, &settings, after(start, 400)), None);
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Open, &settings, after(start, 1_000)), None);
+
+		// nothing is compared until a command is known
+		assert_eq!(monitor.update(ValveState::Fault, ValveState::Undetermined, &settings, after(start, 2_000)), None);
+	}
+
+	#[test]
+	fn retries_are_spaced_by_response_time_and_limited() {
+		let settings = MismatchSettings { action: MismatchAction::Retry(2), ..Default::default() };
+		let mut monitor = MismatchMonitor::default();
+		let start = Instant::now();
+
+		let mut update = |milliseconds| monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, milliseconds));
+
+		assert_eq!(update(0), None);
+		assert_eq!(update(500), Some(MismatchEvent::Raised { retry: true }));
+		assert_eq!(update(900), None);
+		assert_eq!(update(1_000), Some(MismatchEvent::Retry));
+		assert_eq!(update(1_500), None);
+		assert_eq!(update(2_000), None);
+	}
+
+	#[test]
+	fn new_command_restarts_response_time_and_retries() {
+		let settings = MismatchSettings { action: MismatchAction::Retry(1), ..Default::default() };
+		let mut monitor = MismatchMonitor::default();
+		let start = Instant::now();
+
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, start), None);
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 500)), Some(MismatchEvent::Raised { retry: true }));
+
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Closed, &settings, after(start, 600)), None);
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Closed, &settings, after(start, 1_000)), None);
+		assert_eq!(monitor.update(ValveState::Open, ValveState::Closed, &settings, after(start, 1_100)), Some(MismatchEvent::Retry));
+	}
+
+	#[test]
+	fn cancelled_retries_still_alarm() {
+		let settings = MismatchSettings { action: MismatchAction::Retry(3), ..Default::default() };
+		let mut monitor = MismatchMonitor::default();
+		let start = Instant::now();
+
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, start), None);
+		monitor.cancel_retries();
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 500)), Some(MismatchEvent::Raised { retry: false }));
+		assert_eq!(monitor.update(ValveState::Closed, ValveState::Open, &settings, after(start, 1_000)), None);
+	}
+}
@@ -0,0 +1,58 @@
+use jeflog::warn;
+use serde::{Deserialize, Serialize};
+
+/// Identifies which revision of a configuration object the flight computer is using.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConfigVersion {
+	/// Incremented every time the object is received from the server.
+	pub version: u64,
+
+	/// FNV-1a hash of the postcard encoding of the object, which the server can
+	/// compute for its own copy and compare.
+	pub hash: u64,
+}
+
+impl ConfigVersion {
+	/// Bumps the version and rehashes the newly received value.
+	pub fn update<T: Serialize + ?Sized>(&mut self, value: &T) {
+		self.version += 1;
+
+		self.hash = match postcard::to_allocvec(value) {
+			Ok(serialized) => content_hash(&serialized),
+			Err(error) => {
+				warn!("Failed to serialize configuration for hashing: {error}");
+				0
+			},
+		};
+	}
+}
+
+/// Versions of every configuration object received from the server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ConfigVersions {
+	pub mappings: ConfigVersion,
+	pub triggers: ConfigVersion,
+	pub abort_sequence: ConfigVersion,
+	pub safing_sequence: ConfigVersion,
+	pub interlocks: ConfigVersion,
+	pub telemetry_streams: ConfigVersion,
+
+	/// Covers the thresholds of every valve, hashed as a map sorted by `text_id`.
+	pub valve_thresholds: ConfigVersion,
+
+	/// Covers the mismatch settings of every valve, hashed as a map sorted by `text_id`.
+	pub mismatch_settings: ConfigVersion,
+}
+
+/// Computes the 64-bit FNV-1a hash of the given bytes.
+///
+/// Used instead of `DefaultHasher` because its output must be reproducible by
+/// the control server and stable across Rust versions.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+	const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+	const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+	bytes
+		.iter()
+		.fold(OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(PRIME))
+}